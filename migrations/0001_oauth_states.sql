@@ -0,0 +1,7 @@
+-- Nonces handed out as the OAuth `state` by the install request, checked and
+-- deleted again by the /api/auth callback.
+CREATE TABLE OAuthStates(
+    shop TEXT PRIMARY KEY,
+    nonce TEXT NOT NULL,
+    expires_at INTEGER NOT NULL
+);
@@ -0,0 +1,55 @@
+-- Shopify ids used to be stored as REAL. SQLite can't change the type of a column so
+-- the tables are rebuilt with INTEGER ids. LineItems is rebuilt against Orders_new so
+-- dropping the old Orders doesn't cascade into the copied line items; the renames
+-- below point its foreign key back at Orders.
+PRAGMA defer_foreign_keys = true;
+
+CREATE TABLE Orders_new(
+    id INTEGER PRIMARY KEY,
+    first_name TEXT,
+    last_name TEXT,
+    email TEXT,
+    store_name TEXT NOT NULL,
+    FOREIGN KEY (store_name)
+        REFERENCES Stores (name)
+            ON UPDATE CASCADE
+            ON DELETE CASCADE
+);
+INSERT INTO Orders_new SELECT CAST(id AS INTEGER), first_name, last_name, email, store_name FROM Orders;
+
+CREATE TABLE LineItems_new(
+    title TEXT NOT NULL,
+    order_id INTEGER NOT NULL,
+    FOREIGN KEY (order_id)
+        REFERENCES Orders_new (id)
+            ON UPDATE CASCADE
+            ON DELETE CASCADE
+);
+INSERT INTO LineItems_new SELECT title, CAST(order_id AS INTEGER) FROM LineItems;
+
+CREATE TABLE Disputes_new(
+    id INTEGER PRIMARY KEY,
+    order_id INTEGER,
+    type TEXT NOT NULL,
+    amount TEXT NOT NULL,
+    currency TEXT NOT NULL,
+    reason TEXT NOT NULL,
+    status TEXT NOT NULL,
+    initiated_at TEXT NOT NULL,
+    evidence_due_by TEXT NOT NULL,
+    evidence_sent_on TEXT,
+    store_name TEXT NOT NULL,
+    FOREIGN KEY (store_name)
+        REFERENCES Stores (name)
+            ON UPDATE CASCADE
+            ON DELETE CASCADE
+);
+INSERT INTO Disputes_new SELECT CAST(id AS INTEGER), CAST(order_id AS INTEGER), type, amount, currency, reason, status, initiated_at, evidence_due_by, evidence_sent_on, store_name FROM Disputes;
+
+DROP TABLE LineItems;
+DROP TABLE Orders;
+DROP TABLE Disputes;
+
+ALTER TABLE Orders_new RENAME TO Orders;
+ALTER TABLE LineItems_new RENAME TO LineItems;
+ALTER TABLE Disputes_new RENAME TO Disputes;
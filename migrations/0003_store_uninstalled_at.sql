@@ -0,0 +1,2 @@
+-- Set by the app/uninstalled webhook. Stores with it set are skipped by the syncs.
+ALTER TABLE Stores ADD COLUMN uninstalled_at INTEGER;
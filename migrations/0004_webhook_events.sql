@@ -0,0 +1,12 @@
+-- One row per webhook delivery keyed on X-Shopify-Webhook-Id. Rows aren't tied to
+-- Stores so the audit trail outlives the store.
+CREATE TABLE WebhookEvents(
+    id TEXT PRIMARY KEY,
+    topic TEXT NOT NULL,
+    store_name TEXT NOT NULL,
+    received_at INTEGER NOT NULL,
+    triggered_at TEXT,
+    status TEXT NOT NULL
+);
+
+CREATE INDEX WebhookEventsByStore ON WebhookEvents (store_name, received_at);
@@ -0,0 +1,3 @@
+-- Unix milliseconds of the state stored for a dispute, so older webhooks that
+-- arrive late don't overwrite newer state.
+ALTER TABLE Disputes ADD COLUMN updated_at INTEGER;
@@ -0,0 +1,32 @@
+-- Every status a dispute has moved through, filled by triggers on Disputes.
+CREATE TABLE DisputeStatusHistory(
+    dispute_id INTEGER NOT NULL,
+    old_status TEXT,
+    new_status TEXT NOT NULL,
+    changed_at INTEGER NOT NULL,
+    FOREIGN KEY (dispute_id)
+        REFERENCES Disputes (id)
+            ON UPDATE CASCADE
+            ON DELETE CASCADE
+);
+
+CREATE INDEX DisputeStatusHistoryByDispute ON DisputeStatusHistory (dispute_id, changed_at);
+
+-- The history of existing disputes starts at the status they're currently in
+INSERT INTO DisputeStatusHistory
+    SELECT id, NULL, status, COALESCE(updated_at, CAST(strftime('%s', 'now') AS INTEGER) * 1000) FROM Disputes;
+
+CREATE TRIGGER DisputeCreated AFTER INSERT ON Disputes
+BEGIN
+    INSERT INTO DisputeStatusHistory VALUES (
+        NEW.id, NULL, NEW.status, COALESCE(NEW.updated_at, CAST(strftime('%s', 'now') AS INTEGER) * 1000)
+    );
+END;
+
+CREATE TRIGGER DisputeStatusChanged AFTER UPDATE OF status ON Disputes
+WHEN OLD.status IS NOT NEW.status
+BEGIN
+    INSERT INTO DisputeStatusHistory VALUES (
+        NEW.id, OLD.status, NEW.status, COALESCE(NEW.updated_at, CAST(strftime('%s', 'now') AS INTEGER) * 1000)
+    );
+END;
@@ -0,0 +1,18 @@
+ALTER TABLE Orders ADD COLUMN cancelled_at TEXT;
+ALTER TABLE Orders ADD COLUMN cancel_reason TEXT;
+
+-- Refunds aren't tied to Orders as a refund can arrive for an order that was never synced
+CREATE TABLE Refunds(
+    id INTEGER PRIMARY KEY,
+    order_id INTEGER NOT NULL,
+    note TEXT,
+    created_at TEXT NOT NULL,
+    processed_at TEXT,
+    store_name TEXT NOT NULL,
+    FOREIGN KEY (store_name)
+        REFERENCES Stores (name)
+            ON UPDATE CASCADE
+            ON DELETE CASCADE
+);
+
+CREATE INDEX RefundsByOrder ON Refunds (order_id);
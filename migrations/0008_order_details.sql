@@ -0,0 +1,17 @@
+-- Existing orders get these filled in the next time they are updated or backfilled
+ALTER TABLE Orders ADD COLUMN name TEXT;
+ALTER TABLE Orders ADD COLUMN order_number INTEGER;
+ALTER TABLE Orders ADD COLUMN created_at TEXT;
+ALTER TABLE Orders ADD COLUMN processed_at TEXT;
+ALTER TABLE Orders ADD COLUMN total_price TEXT;
+ALTER TABLE Orders ADD COLUMN subtotal_price TEXT;
+ALTER TABLE Orders ADD COLUMN total_tax TEXT;
+ALTER TABLE Orders ADD COLUMN total_discounts TEXT;
+ALTER TABLE Orders ADD COLUMN currency TEXT;
+ALTER TABLE Orders ADD COLUMN presentment_currency TEXT;
+ALTER TABLE Orders ADD COLUMN financial_status TEXT;
+ALTER TABLE Orders ADD COLUMN fulfillment_status TEXT;
+ALTER TABLE Orders ADD COLUMN tags TEXT;
+ALTER TABLE Orders ADD COLUMN source_name TEXT;
+
+CREATE INDEX OrdersByStoreAndDate ON Orders (store_name, created_at);
@@ -0,0 +1,28 @@
+-- LineItems gets the Shopify line item id as its primary key. The line items already
+-- stored only have a title, so they are copied over with a generated id until their
+-- order is next updated, at which point they are replaced with the full line items.
+CREATE TABLE LineItems_new(
+    id INTEGER PRIMARY KEY,
+    order_id INTEGER NOT NULL,
+    title TEXT NOT NULL,
+    product_id INTEGER,
+    variant_id INTEGER,
+    sku TEXT,
+    vendor TEXT,
+    quantity INTEGER,
+    price TEXT,
+    total_discount TEXT,
+    taxable INTEGER,
+    FOREIGN KEY (order_id)
+        REFERENCES Orders (id)
+            ON UPDATE CASCADE
+            ON DELETE CASCADE
+);
+INSERT INTO LineItems_new (order_id, title) SELECT order_id, title FROM LineItems;
+
+DROP TABLE LineItems;
+ALTER TABLE LineItems_new RENAME TO LineItems;
+
+CREATE INDEX LineItemsByOrder ON LineItems (order_id);
+CREATE INDEX LineItemsBySku ON LineItems (sku);
+CREATE INDEX LineItemsByVariant ON LineItems (variant_id);
@@ -0,0 +1,30 @@
+-- Customers get a table of their own that orders and checkouts reference by id.
+-- The names and emails copied onto existing rows can't be tied back to a customer
+-- id, so they are kept until the row is next synced, which sets its customer_id and
+-- clears them. Existing stores get a backfill queued for that in 0017.
+CREATE TABLE Customers(
+    id INTEGER PRIMARY KEY,
+    store_name TEXT NOT NULL,
+    email TEXT,
+    first_name TEXT,
+    last_name TEXT,
+    phone TEXT,
+    accepts_marketing INTEGER,
+    total_spent TEXT,
+    orders_count INTEGER,
+    created_at TEXT,
+    FOREIGN KEY (store_name)
+        REFERENCES Stores (name)
+            ON UPDATE CASCADE
+            ON DELETE CASCADE
+);
+
+CREATE INDEX CustomersByStore ON Customers (store_name);
+
+ALTER TABLE Orders ADD COLUMN customer_id INTEGER REFERENCES Customers (id) ON UPDATE CASCADE ON DELETE SET NULL;
+
+CREATE INDEX OrdersByCustomer ON Orders (customer_id);
+
+ALTER TABLE AbandonedCheckout ADD COLUMN customer_id INTEGER REFERENCES Customers (id) ON UPDATE CASCADE ON DELETE SET NULL;
+
+CREATE INDEX AbandonedCheckoutsByCustomer ON AbandonedCheckout (customer_id);
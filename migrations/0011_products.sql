@@ -0,0 +1,42 @@
+-- Filled in by the product backfill when a store is installed, and kept current by
+-- the products/* webhooks. Stores installed before this haven't granted read_products
+-- and need to be reinstalled to be backfilled, so the backfill 0017 queues for them
+-- leaves products out.
+CREATE TABLE Products(
+    id INTEGER PRIMARY KEY,
+    store_name TEXT NOT NULL,
+    title TEXT NOT NULL,
+    vendor TEXT,
+    product_type TEXT,
+    handle TEXT,
+    status TEXT,
+    tags TEXT,
+    created_at TEXT,
+    updated_at TEXT,
+    FOREIGN KEY (store_name)
+        REFERENCES Stores (name)
+            ON UPDATE CASCADE
+            ON DELETE CASCADE
+);
+
+CREATE INDEX ProductsByStore ON Products (store_name);
+
+CREATE TABLE Variants(
+    id INTEGER PRIMARY KEY,
+    product_id INTEGER NOT NULL,
+    title TEXT NOT NULL,
+    sku TEXT,
+    barcode TEXT,
+    price TEXT NOT NULL,
+    compare_at_price TEXT,
+    inventory_quantity INTEGER,
+    created_at TEXT,
+    updated_at TEXT,
+    FOREIGN KEY (product_id)
+        REFERENCES Products (id)
+            ON UPDATE CASCADE
+            ON DELETE CASCADE
+);
+
+CREATE INDEX VariantsByProduct ON Variants (product_id);
+CREATE INDEX VariantsBySku ON Variants (sku);
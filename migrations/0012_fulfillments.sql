@@ -0,0 +1,23 @@
+-- Tracking numbers and urls are JSON arrays. Existing orders get their fulfillments
+-- filled in the next time they are updated.
+CREATE TABLE Fulfillments(
+    id INTEGER PRIMARY KEY,
+    order_id INTEGER NOT NULL,
+    store_name TEXT NOT NULL,
+    status TEXT,
+    tracking_company TEXT,
+    tracking_numbers TEXT NOT NULL,
+    tracking_urls TEXT NOT NULL,
+    shipment_status TEXT,
+    created_at TEXT,
+    updated_at TEXT,
+    FOREIGN KEY (store_name)
+        REFERENCES Stores (name)
+            ON UPDATE CASCADE
+            ON DELETE CASCADE
+);
+
+CREATE INDEX FulfillmentsByOrder ON Fulfillments (order_id);
+
+-- Disputes are looked up by order to check them against the fulfillments
+CREATE INDEX DisputesByOrder ON Disputes (order_id);
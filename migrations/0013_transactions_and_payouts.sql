@@ -0,0 +1,68 @@
+ALTER TABLE Stores ADD COLUMN last_payout_sync INTEGER;
+
+-- Stores installed before this get the transactions of their orders from the backfill
+-- queued in 0017. Their payouts are imported by the payouts backfill queued in 0022,
+-- which skips them until they are reinstalled and grant read_shopify_payments_payouts.
+-- The payout sync leaves a store alone until that backfill has started.
+CREATE TABLE Transactions(
+    id INTEGER PRIMARY KEY,
+    order_id INTEGER NOT NULL,
+    store_name TEXT NOT NULL,
+    kind TEXT NOT NULL,
+    gateway TEXT,
+    status TEXT,
+    amount TEXT NOT NULL,
+    currency TEXT,
+    authorization TEXT,
+    parent_id INTEGER,
+    processed_at TEXT,
+    created_at TEXT,
+    FOREIGN KEY (store_name)
+        REFERENCES Stores (name)
+            ON UPDATE CASCADE
+            ON DELETE CASCADE
+);
+
+CREATE INDEX TransactionsByOrder ON Transactions (order_id);
+
+CREATE TABLE Payouts(
+    id INTEGER PRIMARY KEY,
+    store_name TEXT NOT NULL,
+    status TEXT NOT NULL,
+    date TEXT NOT NULL,
+    currency TEXT NOT NULL,
+    amount TEXT NOT NULL,
+    FOREIGN KEY (store_name)
+        REFERENCES Stores (name)
+            ON UPDATE CASCADE
+            ON DELETE CASCADE
+);
+
+CREATE INDEX PayoutsByStoreAndDate ON Payouts (store_name, date);
+
+-- source_type and source_id point at what moved the money, e.g. a dispute or a refund
+CREATE TABLE BalanceTransactions(
+    id INTEGER PRIMARY KEY,
+    store_name TEXT NOT NULL,
+    payout_id INTEGER,
+    payout_status TEXT,
+    type TEXT NOT NULL,
+    test INTEGER NOT NULL,
+    currency TEXT NOT NULL,
+    amount TEXT NOT NULL,
+    fee TEXT NOT NULL,
+    net TEXT NOT NULL,
+    source_id INTEGER,
+    source_type TEXT,
+    source_order_id INTEGER,
+    source_order_transaction_id INTEGER,
+    processed_at TEXT,
+    FOREIGN KEY (store_name)
+        REFERENCES Stores (name)
+            ON UPDATE CASCADE
+            ON DELETE CASCADE
+);
+
+CREATE INDEX BalanceTransactionsByPayout ON BalanceTransactions (payout_id);
+CREATE INDEX BalanceTransactionsBySource ON BalanceTransactions (source_type, source_id);
+CREATE INDEX BalanceTransactionsByOrder ON BalanceTransactions (source_order_id);
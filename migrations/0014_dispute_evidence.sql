@@ -0,0 +1,43 @@
+-- Filled in for existing disputes the next time they are updated
+-- Addresses, the product description and fulfillments are kept as the JSON Shopify sends
+CREATE TABLE DisputeEvidence(
+    dispute_id INTEGER PRIMARY KEY,
+    id INTEGER NOT NULL,
+    access_activity_log TEXT,
+    billing_address TEXT,
+    shipping_address TEXT,
+    cancellation_policy_disclosure TEXT,
+    cancellation_rebuttal TEXT,
+    customer_email_address TEXT,
+    customer_first_name TEXT,
+    customer_last_name TEXT,
+    product_description TEXT,
+    refund_policy_disclosure TEXT,
+    refund_refusal_explanation TEXT,
+    uncategorized_text TEXT,
+    fulfillments TEXT NOT NULL,
+    submitted_by_merchant_on TEXT,
+    created_at TEXT,
+    updated_at TEXT,
+    fetched_at INTEGER NOT NULL,
+    FOREIGN KEY (dispute_id)
+        REFERENCES Disputes (id)
+            ON UPDATE CASCADE
+            ON DELETE CASCADE
+);
+
+CREATE TABLE DisputeFileUploads(
+    id INTEGER PRIMARY KEY,
+    dispute_id INTEGER NOT NULL,
+    evidence_type TEXT,
+    file_type TEXT,
+    file_size INTEGER,
+    original_file_name TEXT,
+    url TEXT,
+    FOREIGN KEY (dispute_id)
+        REFERENCES Disputes (id)
+            ON UPDATE CASCADE
+            ON DELETE CASCADE
+);
+
+CREATE INDEX DisputeFileUploadsByDispute ON DisputeFileUploads (dispute_id);
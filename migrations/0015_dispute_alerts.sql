@@ -0,0 +1,12 @@
+CREATE TABLE DisputeAlerts(
+    dispute_id INTEGER NOT NULL,
+    threshold_hours INTEGER NOT NULL,
+    sent_at INTEGER NOT NULL,
+    PRIMARY KEY (dispute_id, threshold_hours),
+    FOREIGN KEY (dispute_id)
+        REFERENCES Disputes (id)
+            ON UPDATE CASCADE
+            ON DELETE CASCADE
+);
+
+CREATE INDEX DisputesByStatus ON Disputes (status);
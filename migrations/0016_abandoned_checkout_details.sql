@@ -0,0 +1,62 @@
+-- Existing checkouts get these filled in when they are next updated
+ALTER TABLE AbandonedCheckout ADD COLUMN token TEXT;
+ALTER TABLE AbandonedCheckout ADD COLUMN created_at TEXT;
+ALTER TABLE AbandonedCheckout ADD COLUMN updated_at TEXT;
+ALTER TABLE AbandonedCheckout ADD COLUMN completed_at TEXT;
+ALTER TABLE AbandonedCheckout ADD COLUMN total_price TEXT;
+ALTER TABLE AbandonedCheckout ADD COLUMN currency TEXT;
+
+ALTER TABLE Orders ADD COLUMN checkout_token TEXT;
+
+CREATE INDEX OrdersByCheckoutToken ON Orders (checkout_token);
+
+CREATE TABLE AbandonedCheckoutLineItems(
+    checkout_id INTEGER NOT NULL,
+    key TEXT,
+    title TEXT NOT NULL,
+    variant_title TEXT,
+    product_id INTEGER,
+    variant_id INTEGER,
+    sku TEXT,
+    vendor TEXT,
+    quantity INTEGER NOT NULL,
+    price TEXT NOT NULL,
+    line_price TEXT,
+    FOREIGN KEY (checkout_id)
+        REFERENCES AbandonedCheckout (id)
+            ON UPDATE CASCADE
+            ON DELETE CASCADE
+);
+
+CREATE INDEX AbandonedCheckoutLineItemsByCheckout ON AbandonedCheckoutLineItems (checkout_id);
+
+-- A checkout counts as recovered once it was completed, or an order was placed through
+-- it or by the same customer after it was abandoned. Timestamps are compared through
+-- strftime as Shopify sends them with the shop's UTC offset.
+CREATE VIEW AbandonedCheckoutRecovery AS
+SELECT *, completed_at IS NOT NULL OR recovered_order_id IS NOT NULL AS recovered
+FROM (
+    SELECT
+        AbandonedCheckout.id AS checkout_id,
+        AbandonedCheckout.store_name,
+        AbandonedCheckout.customer_id,
+        AbandonedCheckout.created_at,
+        AbandonedCheckout.completed_at,
+        AbandonedCheckout.total_price,
+        AbandonedCheckout.currency,
+        (
+            SELECT Orders.id
+            FROM Orders
+            WHERE Orders.store_name = AbandonedCheckout.store_name
+                AND (
+                    Orders.checkout_token = AbandonedCheckout.token
+                    OR (
+                        Orders.customer_id = AbandonedCheckout.customer_id
+                        AND strftime('%s', Orders.created_at) >= strftime('%s', AbandonedCheckout.created_at)
+                    )
+                )
+            ORDER BY strftime('%s', Orders.created_at)
+            LIMIT 1
+        ) AS recovered_order_id
+    FROM AbandonedCheckout
+);
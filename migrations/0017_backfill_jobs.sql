@@ -0,0 +1,24 @@
+CREATE TABLE BackfillJobs(
+    store_name TEXT NOT NULL,
+    resource TEXT NOT NULL,
+    -- The url of the next page, or the last id done for per-order and per-dispute resources
+    cursor TEXT,
+    status TEXT NOT NULL,
+    attempts INTEGER NOT NULL,
+    last_error TEXT,
+    updated_at INTEGER NOT NULL,
+    PRIMARY KEY (store_name, resource),
+    FOREIGN KEY (store_name)
+        REFERENCES Stores (name)
+            ON UPDATE CASCADE
+            ON DELETE CASCADE
+);
+
+-- Stores installed before this were backfilled during the install, but their orders
+-- and checkouts predate Customers and still need their customer_id filled in, so
+-- they get a backfill too. Products are left out, as the stores installed before 0011
+-- haven't granted read_products and would fail on them, which holds up the jobs after
+INSERT INTO BackfillJobs (store_name, resource, cursor, status, attempts, last_error, updated_at)
+SELECT Stores.name, resources.column1, NULL, 'pending', 0, NULL, CAST(strftime('%s', 'now') AS INTEGER)
+FROM Stores, (VALUES ('customers'), ('orders'), ('transactions'), ('disputes'), ('dispute_evidence')) AS resources
+WHERE Stores.uninstalled_at IS NULL;
@@ -0,0 +1,3 @@
+-- 'rest' or 'bulk', how orders are backfilled. Switched per store by POSTing to
+-- /api/backfill/<store>?method=bulk, which restarts its backfill
+ALTER TABLE Stores ADD COLUMN backfill_method TEXT NOT NULL DEFAULT 'rest';
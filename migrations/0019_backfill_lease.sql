@@ -0,0 +1,4 @@
+-- Until when a backfill run has the store's jobs to itself. A run can outlast the
+-- minute between backfill crons, and the next one skips the store instead of fetching
+-- the same pages alongside it
+ALTER TABLE Stores ADD COLUMN backfill_locked_until INTEGER;
@@ -0,0 +1,46 @@
+-- Synced checkouts keep the email entered at checkout, where before they cleared the
+-- column, so guest checkouts can be matched to orders by it. Existing checkouts get
+-- theirs on their next sync
+CREATE INDEX CustomersByEmail ON Customers (store_name, email);
+
+DROP VIEW AbandonedCheckoutRecovery;
+
+-- A checkout counts as recovered once it was completed, or an order was placed through
+-- it or by the same customer after it was abandoned. Guest checkouts have no customer
+-- and are matched to the customer with their email instead. Timestamps are compared
+-- through strftime as Shopify sends them with the shop's UTC offset.
+CREATE VIEW AbandonedCheckoutRecovery AS
+SELECT *, completed_at IS NOT NULL OR recovered_order_id IS NOT NULL AS recovered
+FROM (
+    SELECT
+        AbandonedCheckout.id AS checkout_id,
+        AbandonedCheckout.store_name,
+        AbandonedCheckout.customer_id,
+        AbandonedCheckout.created_at,
+        AbandonedCheckout.completed_at,
+        AbandonedCheckout.total_price,
+        AbandonedCheckout.currency,
+        (
+            SELECT Orders.id
+            FROM Orders
+            WHERE Orders.store_name = AbandonedCheckout.store_name
+                AND (
+                    Orders.checkout_token = AbandonedCheckout.token
+                    OR (
+                        (
+                            Orders.customer_id = AbandonedCheckout.customer_id
+                            OR Orders.customer_id IN (
+                                SELECT Customers.id
+                                FROM Customers
+                                WHERE Customers.store_name = AbandonedCheckout.store_name
+                                    AND Customers.email = AbandonedCheckout.email
+                            )
+                        )
+                        AND strftime('%s', Orders.created_at) >= strftime('%s', AbandonedCheckout.created_at)
+                    )
+                )
+            ORDER BY strftime('%s', Orders.created_at)
+            LIMIT 1
+        ) AS recovered_order_id
+    FROM AbandonedCheckout
+);
@@ -0,0 +1,21 @@
+-- What a refund gave back: the line items it refunds, and the transactions that paid
+-- it out through Transactions.refund_id. Refunds stored before this have neither
+CREATE TABLE RefundLineItems(
+    id INTEGER PRIMARY KEY,
+    refund_id INTEGER NOT NULL,
+    line_item_id INTEGER NOT NULL,
+    quantity INTEGER NOT NULL,
+    restock_type TEXT,
+    subtotal TEXT,
+    total_tax TEXT,
+    FOREIGN KEY (refund_id)
+        REFERENCES Refunds (id)
+            ON UPDATE CASCADE
+            ON DELETE CASCADE
+);
+
+CREATE INDEX RefundLineItemsByRefund ON RefundLineItems (refund_id);
+
+ALTER TABLE Transactions ADD COLUMN refund_id INTEGER;
+
+CREATE INDEX TransactionsByRefund ON Transactions (refund_id);
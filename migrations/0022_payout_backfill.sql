@@ -0,0 +1,7 @@
+-- Payout history is imported page by page by a payouts backfill job instead of in one
+-- go by the first payout sync, which only takes over once that job has started. Stores
+-- whose first sync never got through get the job here
+INSERT OR IGNORE INTO BackfillJobs (store_name, resource, cursor, status, attempts, last_error, updated_at)
+SELECT name, 'payouts', NULL, 'pending', 0, NULL, CAST(strftime('%s', 'now') AS INTEGER)
+FROM Stores
+WHERE uninstalled_at IS NULL AND last_payout_sync IS NULL;
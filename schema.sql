@@ -1,52 +1,372 @@
+DROP VIEW IF EXISTS AbandonedCheckoutRecovery;
+DROP TABLE IF EXISTS AbandonedCheckoutLineItems;
+DROP TABLE IF EXISTS BackfillJobs;
+DROP TABLE IF EXISTS DisputeStatusHistory;
+DROP TABLE IF EXISTS DisputeAlerts;
+DROP TABLE IF EXISTS DisputeFileUploads;
+DROP TABLE IF EXISTS DisputeEvidence;
 DROP TABLE IF EXISTS LineItems;
+DROP TABLE IF EXISTS RefundLineItems;
+DROP TABLE IF EXISTS Refunds;
+DROP TABLE IF EXISTS Transactions;
+DROP TABLE IF EXISTS BalanceTransactions;
+DROP TABLE IF EXISTS Payouts;
+DROP TABLE IF EXISTS Fulfillments;
 DROP TABLE IF EXISTS Orders;
 DROP TABLE IF EXISTS AbandonedCheckout;
+DROP TABLE IF EXISTS Customers;
+DROP TABLE IF EXISTS Variants;
+DROP TABLE IF EXISTS Products;
 DROP TABLE IF EXISTS Disputes;
 DROP TABLE IF EXISTS Stores;
+DROP TABLE IF EXISTS OAuthStates;
+DROP TABLE IF EXISTS WebhookEvents;
 
 CREATE TABLE Stores(
     name TEXT PRIMARY KEY,
     access_token TEXT NOT NULL,
-    last_abandoned_checkout_sync INTEGER
+    last_abandoned_checkout_sync INTEGER,
+    last_payout_sync INTEGER,
+    uninstalled_at INTEGER,
+    -- 'rest' or 'bulk', how orders are backfilled
+    backfill_method TEXT NOT NULL DEFAULT 'rest',
+    -- Until when a backfill run has the store's jobs to itself
+    backfill_locked_until INTEGER
 );
 
+CREATE TABLE Customers(
+    id INTEGER PRIMARY KEY,
+    store_name TEXT NOT NULL,
+    email TEXT,
+    first_name TEXT,
+    last_name TEXT,
+    phone TEXT,
+    accepts_marketing INTEGER,
+    total_spent TEXT,
+    orders_count INTEGER,
+    created_at TEXT,
+    FOREIGN KEY (store_name)
+        REFERENCES Stores (name)
+            ON UPDATE CASCADE
+            ON DELETE CASCADE
+);
+
+CREATE INDEX CustomersByStore ON Customers (store_name);
+CREATE INDEX CustomersByEmail ON Customers (store_name, email);
+
 CREATE TABLE Orders(
-    id REAL PRIMARY KEY,
+    id INTEGER PRIMARY KEY,
+    customer_id INTEGER,
+    store_name TEXT NOT NULL,
+    cancelled_at TEXT,
+    cancel_reason TEXT,
+    name TEXT,
+    order_number INTEGER,
+    created_at TEXT,
+    processed_at TEXT,
+    total_price TEXT,
+    subtotal_price TEXT,
+    total_tax TEXT,
+    total_discounts TEXT,
+    currency TEXT,
+    presentment_currency TEXT,
+    financial_status TEXT,
+    fulfillment_status TEXT,
+    tags TEXT,
+    source_name TEXT,
+    checkout_token TEXT,
+    -- Copied from the customer before orders referenced Customers. Cleared once the
+    -- order is synced again
     first_name TEXT,
     last_name TEXT,
     email TEXT,
-    store_name TEXT NOT NULL,
     FOREIGN KEY (store_name)
         REFERENCES Stores (name)
             ON UPDATE CASCADE
-            ON DELETE CASCADE
+            ON DELETE CASCADE,
+    FOREIGN KEY (customer_id)
+        REFERENCES Customers (id)
+            ON UPDATE CASCADE
+            ON DELETE SET NULL
 );
 
+CREATE INDEX OrdersByStoreAndDate ON Orders (store_name, created_at);
+CREATE INDEX OrdersByCustomer ON Orders (customer_id);
+CREATE INDEX OrdersByCheckoutToken ON Orders (checkout_token);
+
 CREATE TABLE LineItems(
+    id INTEGER PRIMARY KEY,
+    order_id INTEGER NOT NULL,
     title TEXT NOT NULL,
-    order_id REAL NOT NULL,
+    product_id INTEGER,
+    variant_id INTEGER,
+    sku TEXT,
+    vendor TEXT,
+    quantity INTEGER,
+    price TEXT,
+    total_discount TEXT,
+    taxable INTEGER,
     FOREIGN KEY (order_id)
         REFERENCES Orders (id)
             ON UPDATE CASCADE
             ON DELETE CASCADE
 );
 
+CREATE INDEX LineItemsByOrder ON LineItems (order_id);
+CREATE INDEX LineItemsBySku ON LineItems (sku);
+CREATE INDEX LineItemsByVariant ON LineItems (variant_id);
+
+-- Like refunds these can arrive for orders that were never synced, so there is no
+-- foreign key to Orders. Tracking numbers and urls are JSON arrays.
+CREATE TABLE Fulfillments(
+    id INTEGER PRIMARY KEY,
+    order_id INTEGER NOT NULL,
+    store_name TEXT NOT NULL,
+    status TEXT,
+    tracking_company TEXT,
+    tracking_numbers TEXT NOT NULL,
+    tracking_urls TEXT NOT NULL,
+    shipment_status TEXT,
+    created_at TEXT,
+    updated_at TEXT,
+    FOREIGN KEY (store_name)
+        REFERENCES Stores (name)
+            ON UPDATE CASCADE
+            ON DELETE CASCADE
+);
+
+CREATE INDEX FulfillmentsByOrder ON Fulfillments (order_id);
+
+CREATE TABLE Products(
+    id INTEGER PRIMARY KEY,
+    store_name TEXT NOT NULL,
+    title TEXT NOT NULL,
+    vendor TEXT,
+    product_type TEXT,
+    handle TEXT,
+    status TEXT,
+    tags TEXT,
+    created_at TEXT,
+    updated_at TEXT,
+    FOREIGN KEY (store_name)
+        REFERENCES Stores (name)
+            ON UPDATE CASCADE
+            ON DELETE CASCADE
+);
+
+CREATE INDEX ProductsByStore ON Products (store_name);
+
+CREATE TABLE Variants(
+    id INTEGER PRIMARY KEY,
+    product_id INTEGER NOT NULL,
+    title TEXT NOT NULL,
+    sku TEXT,
+    barcode TEXT,
+    price TEXT NOT NULL,
+    compare_at_price TEXT,
+    inventory_quantity INTEGER,
+    created_at TEXT,
+    updated_at TEXT,
+    FOREIGN KEY (product_id)
+        REFERENCES Products (id)
+            ON UPDATE CASCADE
+            ON DELETE CASCADE
+);
+
+CREATE INDEX VariantsByProduct ON Variants (product_id);
+CREATE INDEX VariantsBySku ON Variants (sku);
+
+CREATE TABLE Refunds(
+    id INTEGER PRIMARY KEY,
+    order_id INTEGER NOT NULL,
+    note TEXT,
+    created_at TEXT NOT NULL,
+    processed_at TEXT,
+    store_name TEXT NOT NULL,
+    FOREIGN KEY (store_name)
+        REFERENCES Stores (name)
+            ON UPDATE CASCADE
+            ON DELETE CASCADE
+);
+
+CREATE INDEX RefundsByOrder ON Refunds (order_id);
+
+-- Amounts are in the shop's currency
+CREATE TABLE RefundLineItems(
+    id INTEGER PRIMARY KEY,
+    refund_id INTEGER NOT NULL,
+    line_item_id INTEGER NOT NULL,
+    quantity INTEGER NOT NULL,
+    restock_type TEXT,
+    subtotal TEXT,
+    total_tax TEXT,
+    FOREIGN KEY (refund_id)
+        REFERENCES Refunds (id)
+            ON UPDATE CASCADE
+            ON DELETE CASCADE
+);
+
+CREATE INDEX RefundLineItemsByRefund ON RefundLineItems (refund_id);
+
+-- No foreign key to Orders for the same reason as Fulfillments
+CREATE TABLE Transactions(
+    id INTEGER PRIMARY KEY,
+    order_id INTEGER NOT NULL,
+    store_name TEXT NOT NULL,
+    kind TEXT NOT NULL,
+    gateway TEXT,
+    status TEXT,
+    amount TEXT NOT NULL,
+    currency TEXT,
+    authorization TEXT,
+    parent_id INTEGER,
+    processed_at TEXT,
+    created_at TEXT,
+    -- Set for the transactions that came with a refund, which is what it paid back
+    refund_id INTEGER,
+    FOREIGN KEY (store_name)
+        REFERENCES Stores (name)
+            ON UPDATE CASCADE
+            ON DELETE CASCADE
+);
+
+CREATE INDEX TransactionsByOrder ON Transactions (order_id);
+CREATE INDEX TransactionsByRefund ON Transactions (refund_id);
+
+CREATE TABLE Payouts(
+    id INTEGER PRIMARY KEY,
+    store_name TEXT NOT NULL,
+    status TEXT NOT NULL,
+    date TEXT NOT NULL,
+    currency TEXT NOT NULL,
+    amount TEXT NOT NULL,
+    FOREIGN KEY (store_name)
+        REFERENCES Stores (name)
+            ON UPDATE CASCADE
+            ON DELETE CASCADE
+);
+
+CREATE INDEX PayoutsByStoreAndDate ON Payouts (store_name, date);
+
+-- source_type and source_id point at what moved the money, e.g. a dispute or a refund
+CREATE TABLE BalanceTransactions(
+    id INTEGER PRIMARY KEY,
+    store_name TEXT NOT NULL,
+    payout_id INTEGER,
+    payout_status TEXT,
+    type TEXT NOT NULL,
+    test INTEGER NOT NULL,
+    currency TEXT NOT NULL,
+    amount TEXT NOT NULL,
+    fee TEXT NOT NULL,
+    net TEXT NOT NULL,
+    source_id INTEGER,
+    source_type TEXT,
+    source_order_id INTEGER,
+    source_order_transaction_id INTEGER,
+    processed_at TEXT,
+    FOREIGN KEY (store_name)
+        REFERENCES Stores (name)
+            ON UPDATE CASCADE
+            ON DELETE CASCADE
+);
+
+CREATE INDEX BalanceTransactionsByPayout ON BalanceTransactions (payout_id);
+CREATE INDEX BalanceTransactionsBySource ON BalanceTransactions (source_type, source_id);
+CREATE INDEX BalanceTransactionsByOrder ON BalanceTransactions (source_order_id);
+
 CREATE TABLE AbandonedCheckout(
     id INTEGER PRIMARY KEY,
     checkout_url TEXT NOT NULL,
+    customer_id INTEGER,
+    store_name TEXT NOT NULL,
+    token TEXT,
+    created_at TEXT,
+    updated_at TEXT,
+    completed_at TEXT,
+    total_price TEXT,
+    currency TEXT,
+    -- Copied from the customer before checkouts referenced Customers. Cleared once
+    -- the checkout is synced again
     first_name TEXT,
     last_name TEXT,
+    -- Entered at checkout, which guests do too
     email TEXT,
-    store_name TEXT NOT NULL,
     FOREIGN KEY (store_name)
         REFERENCES Stores (name)
+            ON UPDATE CASCADE
+            ON DELETE CASCADE,
+    FOREIGN KEY (customer_id)
+        REFERENCES Customers (id)
+            ON UPDATE CASCADE
+            ON DELETE SET NULL
+);
+
+CREATE INDEX AbandonedCheckoutsByCustomer ON AbandonedCheckout (customer_id);
+
+CREATE TABLE AbandonedCheckoutLineItems(
+    checkout_id INTEGER NOT NULL,
+    key TEXT,
+    title TEXT NOT NULL,
+    variant_title TEXT,
+    product_id INTEGER,
+    variant_id INTEGER,
+    sku TEXT,
+    vendor TEXT,
+    quantity INTEGER NOT NULL,
+    price TEXT NOT NULL,
+    line_price TEXT,
+    FOREIGN KEY (checkout_id)
+        REFERENCES AbandonedCheckout (id)
             ON UPDATE CASCADE
             ON DELETE CASCADE
 );
 
+CREATE INDEX AbandonedCheckoutLineItemsByCheckout ON AbandonedCheckoutLineItems (checkout_id);
+
+-- A checkout counts as recovered once it was completed, or an order was placed through
+-- it or by the same customer after it was abandoned. Guest checkouts have no customer
+-- and are matched to the customer with their email instead. Timestamps are compared
+-- through strftime as Shopify sends them with the shop's UTC offset.
+CREATE VIEW AbandonedCheckoutRecovery AS
+SELECT *, completed_at IS NOT NULL OR recovered_order_id IS NOT NULL AS recovered
+FROM (
+    SELECT
+        AbandonedCheckout.id AS checkout_id,
+        AbandonedCheckout.store_name,
+        AbandonedCheckout.customer_id,
+        AbandonedCheckout.created_at,
+        AbandonedCheckout.completed_at,
+        AbandonedCheckout.total_price,
+        AbandonedCheckout.currency,
+        (
+            SELECT Orders.id
+            FROM Orders
+            WHERE Orders.store_name = AbandonedCheckout.store_name
+                AND (
+                    Orders.checkout_token = AbandonedCheckout.token
+                    OR (
+                        (
+                            Orders.customer_id = AbandonedCheckout.customer_id
+                            OR Orders.customer_id IN (
+                                SELECT Customers.id
+                                FROM Customers
+                                WHERE Customers.store_name = AbandonedCheckout.store_name
+                                    AND Customers.email = AbandonedCheckout.email
+                            )
+                        )
+                        AND strftime('%s', Orders.created_at) >= strftime('%s', AbandonedCheckout.created_at)
+                    )
+                )
+            ORDER BY strftime('%s', Orders.created_at)
+            LIMIT 1
+        ) AS recovered_order_id
+    FROM AbandonedCheckout
+);
+
 CREATE TABLE Disputes(
-    id REAL PRIMARY KEY,
-    order_id REAL,
+    id INTEGER PRIMARY KEY,
+    order_id INTEGER,
     type TEXT NOT NULL,
     amount TEXT NOT NULL,
     currency TEXT NOT NULL,
@@ -56,6 +376,129 @@ CREATE TABLE Disputes(
     evidence_due_by TEXT NOT NULL,
     evidence_sent_on TEXT,
     store_name TEXT NOT NULL,
+    updated_at INTEGER,
+    FOREIGN KEY (store_name)
+        REFERENCES Stores (name)
+            ON UPDATE CASCADE
+            ON DELETE CASCADE
+);
+
+CREATE INDEX DisputesByOrder ON Disputes (order_id);
+
+CREATE TABLE DisputeStatusHistory(
+    dispute_id INTEGER NOT NULL,
+    old_status TEXT,
+    new_status TEXT NOT NULL,
+    changed_at INTEGER NOT NULL,
+    FOREIGN KEY (dispute_id)
+        REFERENCES Disputes (id)
+            ON UPDATE CASCADE
+            ON DELETE CASCADE
+);
+
+CREATE INDEX DisputeStatusHistoryByDispute ON DisputeStatusHistory (dispute_id, changed_at);
+
+-- Every write to Disputes goes through an insert or an upsert, so recording the
+-- transitions here catches all of them. changed_at is the version of the new state
+-- in unix milliseconds, falling back to the current time.
+CREATE TRIGGER DisputeCreated AFTER INSERT ON Disputes
+BEGIN
+    INSERT INTO DisputeStatusHistory VALUES (
+        NEW.id, NULL, NEW.status, COALESCE(NEW.updated_at, CAST(strftime('%s', 'now') AS INTEGER) * 1000)
+    );
+END;
+
+CREATE TRIGGER DisputeStatusChanged AFTER UPDATE OF status ON Disputes
+WHEN OLD.status IS NOT NEW.status
+BEGIN
+    INSERT INTO DisputeStatusHistory VALUES (
+        NEW.id, OLD.status, NEW.status, COALESCE(NEW.updated_at, CAST(strftime('%s', 'now') AS INTEGER) * 1000)
+    );
+END;
+
+-- Addresses, the product description and fulfillments are kept as the JSON Shopify sends
+CREATE TABLE DisputeEvidence(
+    dispute_id INTEGER PRIMARY KEY,
+    id INTEGER NOT NULL,
+    access_activity_log TEXT,
+    billing_address TEXT,
+    shipping_address TEXT,
+    cancellation_policy_disclosure TEXT,
+    cancellation_rebuttal TEXT,
+    customer_email_address TEXT,
+    customer_first_name TEXT,
+    customer_last_name TEXT,
+    product_description TEXT,
+    refund_policy_disclosure TEXT,
+    refund_refusal_explanation TEXT,
+    uncategorized_text TEXT,
+    fulfillments TEXT NOT NULL,
+    submitted_by_merchant_on TEXT,
+    created_at TEXT,
+    updated_at TEXT,
+    fetched_at INTEGER NOT NULL,
+    FOREIGN KEY (dispute_id)
+        REFERENCES Disputes (id)
+            ON UPDATE CASCADE
+            ON DELETE CASCADE
+);
+
+CREATE TABLE DisputeFileUploads(
+    id INTEGER PRIMARY KEY,
+    dispute_id INTEGER NOT NULL,
+    evidence_type TEXT,
+    file_type TEXT,
+    file_size INTEGER,
+    original_file_name TEXT,
+    url TEXT,
+    FOREIGN KEY (dispute_id)
+        REFERENCES Disputes (id)
+            ON UPDATE CASCADE
+            ON DELETE CASCADE
+);
+
+CREATE INDEX DisputeFileUploadsByDispute ON DisputeFileUploads (dispute_id);
+
+CREATE TABLE DisputeAlerts(
+    dispute_id INTEGER NOT NULL,
+    threshold_hours INTEGER NOT NULL,
+    sent_at INTEGER NOT NULL,
+    PRIMARY KEY (dispute_id, threshold_hours),
+    FOREIGN KEY (dispute_id)
+        REFERENCES Disputes (id)
+            ON UPDATE CASCADE
+            ON DELETE CASCADE
+);
+
+CREATE INDEX DisputesByStatus ON Disputes (status);
+
+CREATE TABLE OAuthStates(
+    shop TEXT PRIMARY KEY,
+    nonce TEXT NOT NULL,
+    expires_at INTEGER NOT NULL
+);
+
+CREATE TABLE WebhookEvents(
+    id TEXT PRIMARY KEY,
+    topic TEXT NOT NULL,
+    store_name TEXT NOT NULL,
+    received_at INTEGER NOT NULL,
+    triggered_at TEXT,
+    status TEXT NOT NULL
+);
+
+CREATE INDEX WebhookEventsByStore ON WebhookEvents (store_name, received_at);
+
+CREATE TABLE BackfillJobs(
+    store_name TEXT NOT NULL,
+    resource TEXT NOT NULL,
+    -- The url of the next page, or the last id done for per-order and per-dispute resources
+    cursor TEXT,
+    status TEXT NOT NULL,
+    attempts INTEGER NOT NULL,
+    last_error TEXT,
+    updated_at INTEGER NOT NULL,
+    PRIMARY KEY (store_name, resource),
     FOREIGN KEY (store_name)
         REFERENCES Stores (name)
             ON UPDATE CASCADE
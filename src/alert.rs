@@ -0,0 +1,187 @@
+//! Warns the merchant before the evidence of a dispute is due. Every dispute that
+//! still needs a response is checked against the thresholds in
+//! `DISPUTE_ALERT_THRESHOLDS_HOURS`, and once its deadline is closer than one of them a
+//! Slack-compatible message is posted to `DISPUTE_ALERT_WEBHOOK_URL`. Sent alerts are
+//! recorded in `DisputeAlerts` so each threshold only fires once per dispute.
+
+use worker::{Env, Fetch, Headers, Method, Request, RequestInit};
+
+use crate::{repo, unix_millis, Result, DB_BINDING};
+
+const DEFAULT_THRESHOLDS_HOURS: [i64; 2] = [72, 24];
+
+fn thresholds_hours(env: &Env) -> Vec<i64> {
+    let var = env
+        .var("DISPUTE_ALERT_THRESHOLDS_HOURS")
+        .map(|var| var.to_string())
+        .ok();
+
+    parse_thresholds_hours(var.as_deref())
+}
+
+/// Reads the comma separated thresholds, e.g. `"72,24"`, sorted from the tightest.
+fn parse_thresholds_hours(var: Option<&str>) -> Vec<i64> {
+    let mut thresholds = var
+        .map(|var| {
+            var.split(',')
+                .filter_map(|hours| hours.trim().parse().ok())
+                .filter(|&hours| hours > 0)
+                .collect::<Vec<i64>>()
+        })
+        .unwrap_or_default();
+
+    if thresholds.is_empty() {
+        thresholds = DEFAULT_THRESHOLDS_HOURS.to_vec();
+    }
+
+    thresholds.sort_unstable();
+    thresholds.dedup();
+    thresholds
+}
+
+/// The threshold to alert for with `seconds_left` until the evidence is due, if any.
+///
+/// Only the tightest threshold that has been crossed is alerted, so a dispute that is
+/// first seen close to its deadline doesn't get an alert for every threshold. Disputes
+/// past their deadline get none.
+fn crossed_threshold(thresholds: &[i64], seconds_left: i64) -> Option<i64> {
+    if seconds_left <= 0 {
+        return None;
+    }
+
+    thresholds
+        .iter()
+        .copied()
+        .find(|&hours| seconds_left <= hours * 60 * 60)
+}
+
+pub async fn alert_dispute_deadlines(env: &Env) -> Result<()> {
+    let webhook_url = match env.secret("DISPUTE_ALERT_WEBHOOK_URL") {
+        Ok(url) => url.to_string(),
+        // Alerting is off until a destination is configured
+        Err(_) => return Ok(()),
+    };
+
+    let thresholds = thresholds_hours(env);
+    let db = env.d1(DB_BINDING)?;
+    let now = time::OffsetDateTime::now_utc().unix_timestamp();
+
+    for dispute in repo::disputes_awaiting_evidence(&db).await? {
+        let due_by = match unix_millis(&dispute.evidence_due_by) {
+            Some(due_by) => due_by / 1000,
+            None => continue,
+        };
+
+        let seconds_left = due_by - now;
+        let threshold = match crossed_threshold(&thresholds, seconds_left) {
+            Some(threshold) => threshold,
+            None => continue,
+        };
+
+        if repo::dispute_alert_sent(&db, dispute.id, threshold).await? {
+            continue;
+        }
+
+        let text = format!(
+            "Dispute {} on {} ({} {}, {}) needs evidence by {}, {} hours from now.{}",
+            dispute.id,
+            dispute.store_name,
+            dispute.amount,
+            dispute.currency,
+            dispute.reason,
+            dispute.evidence_due_by,
+            seconds_left / (60 * 60),
+            dispute
+                .order_id
+                .map(|order_id| format!(" Order {order_id}."))
+                .unwrap_or_default(),
+        );
+
+        // An alert that fails to send isn't recorded, so it is retried on the next run,
+        // and the other disputes still get theirs
+        match post_alert(&webhook_url, &text).await {
+            Ok(true) => repo::record_dispute_alert(&db, dispute.id, threshold, now).await?,
+            Ok(false) => {}
+            Err(e) => {
+                worker::console_error!("Dispute alert for dispute {} failed: {e}", dispute.id);
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Posts `{"text": ...}`, which Slack incoming webhooks and most chat tools accept.
+/// Returns whether the webhook accepted it.
+async fn post_alert(url: &str, text: &str) -> Result<bool> {
+    let resp = Fetch::Request(Request::new_with_init(
+        url,
+        &RequestInit {
+            body: Some(serde_json::json!({ "text": text }).to_string().into()),
+            method: Method::Post,
+            headers: {
+                let mut headers = Headers::default();
+                headers.append("Content-Type", "application/json")?;
+
+                headers
+            },
+            ..Default::default()
+        },
+    )?)
+    .send()
+    .await?;
+
+    let status = resp.status_code();
+    if !(200..300).contains(&status) {
+        worker::console_error!("Dispute alert webhook responded with {status}");
+    }
+
+    Ok((200..300).contains(&status))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HOUR: i64 = 60 * 60;
+
+    #[test]
+    fn parses_thresholds_tightest_first() {
+        assert_eq!(parse_thresholds_hours(Some("72, 24,48")), vec![24, 48, 72]);
+    }
+
+    #[test]
+    fn skips_unusable_thresholds() {
+        assert_eq!(parse_thresholds_hours(Some("24,abc,-1,0,24")), vec![24]);
+    }
+
+    #[test]
+    fn falls_back_to_the_default_thresholds() {
+        assert_eq!(parse_thresholds_hours(None), vec![24, 72]);
+        assert_eq!(parse_thresholds_hours(Some("")), vec![24, 72]);
+        assert_eq!(parse_thresholds_hours(Some("none")), vec![24, 72]);
+    }
+
+    #[test]
+    fn alerts_nothing_before_the_widest_threshold() {
+        assert_eq!(crossed_threshold(&[24, 72], 72 * HOUR + 1), None);
+    }
+
+    #[test]
+    fn alerts_a_threshold_from_exactly_when_it_is_crossed() {
+        assert_eq!(crossed_threshold(&[24, 72], 72 * HOUR), Some(72));
+        assert_eq!(crossed_threshold(&[24, 72], 24 * HOUR + 1), Some(72));
+        assert_eq!(crossed_threshold(&[24, 72], 24 * HOUR), Some(24));
+    }
+
+    #[test]
+    fn alerts_only_the_tightest_threshold_when_first_seen_inside_it() {
+        assert_eq!(crossed_threshold(&[24, 72], 2 * HOUR), Some(24));
+    }
+
+    #[test]
+    fn alerts_nothing_past_the_due_date() {
+        assert_eq!(crossed_threshold(&[24, 72], 0), None);
+        assert_eq!(crossed_threshold(&[24, 72], -HOUR), None);
+    }
+}
@@ -0,0 +1,417 @@
+//! The import of a store's history. Installing only queues a job per resource in
+//! `BackfillJobs`; the backfill cron then works through them one page at a time and
+//! saves the cursor of the next page after each one, so a run that fails or is cut off
+//! picks up where it stopped instead of starting over.
+//!
+//! Orders are either paged through the REST API or, for stores set to the `bulk`
+//! backfill method, fetched with a GraphQL bulk operation, see [`crate::bulk`].
+
+use worker::{D1Database, Env};
+
+use crate::{
+    bulk,
+    customer::Customers,
+    dispute::{DisputeEvidence, Disputes},
+    order::Orders,
+    payout,
+    product::Products,
+    repo,
+    shopify_id::ShopifyId,
+    transaction::Transactions,
+    Result, Token, DB_BINDING,
+};
+
+/// Pages worked through per run, across every store, as they all share the Worker's
+/// subrequest limit. A page is a call, or a call per id on the per-id pages, so a run
+/// makes at most about `PAGES_PER_RUN * (IDS_PER_PAGE + 1)` calls. That can take a few
+/// minutes, longer than the minute between runs, hence [`LEASE_SECS`].
+const PAGES_PER_RUN: usize = 10;
+/// How long a run holds a store's backfill. Well over what `PAGES_PER_RUN` pages take, so
+/// it only runs out on its own when a run was cut off before releasing it.
+const LEASE_SECS: i64 = 15 * 60;
+/// Orders or disputes whose transactions or evidence are fetched per page. Each one is
+/// a call of its own.
+const IDS_PER_PAGE: u32 = 25;
+/// Failures in a row after which a job is given up on until the store is reinstalled.
+const MAX_ATTEMPTS: i64 = 10;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Resource {
+    Customers,
+    Products,
+    Orders,
+    /// Orders with their line items and customers, through a bulk operation.
+    BulkOrders,
+    /// Listed per order, for the orders the `Orders` or `BulkOrders` job stored.
+    Transactions,
+    Disputes,
+    /// Fetched per dispute, for the disputes the `Disputes` job stored.
+    DisputeEvidence,
+    /// Payouts with their balance transactions. Once it starts, the payout sync picks up
+    /// the payouts made after it.
+    Payouts,
+}
+
+impl Resource {
+    /// Every resource, in the order a store's jobs run in.
+    pub const ALL: [Resource; 8] = [
+        Resource::Customers,
+        Resource::Products,
+        Resource::Orders,
+        Resource::BulkOrders,
+        Resource::Transactions,
+        Resource::Disputes,
+        Resource::DisputeEvidence,
+        Resource::Payouts,
+    ];
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Resource::Customers => "customers",
+            Resource::Products => "products",
+            Resource::Orders => "orders",
+            Resource::BulkOrders => "bulk_orders",
+            Resource::Transactions => "transactions",
+            Resource::Disputes => "disputes",
+            Resource::DisputeEvidence => "dispute_evidence",
+            Resource::Payouts => "payouts",
+        }
+    }
+
+    fn from_str(s: &str) -> Option<Self> {
+        Resource::ALL
+            .into_iter()
+            .find(|resource| resource.as_str() == s)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum JobStatus {
+    /// Queued or part way through. Picked up by the next run.
+    Pending,
+    Done,
+    /// Failed `MAX_ATTEMPTS` times in a row.
+    Failed,
+}
+
+impl JobStatus {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            JobStatus::Pending => "pending",
+            JobStatus::Done => "done",
+            JobStatus::Failed => "failed",
+        }
+    }
+}
+
+/// How a store's orders are backfilled, from `Stores.backfill_method`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Method {
+    /// Paged through `orders.json`.
+    Rest,
+    /// A GraphQL bulk operation, for stores with a long history.
+    Bulk,
+}
+
+impl Method {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Method::Rest => "rest",
+            Method::Bulk => "bulk",
+        }
+    }
+
+    pub fn from_str(s: &str) -> Option<Self> {
+        match s {
+            "rest" => Some(Method::Rest),
+            "bulk" => Some(Method::Bulk),
+            _ => None,
+        }
+    }
+
+    fn resources(self) -> Vec<Resource> {
+        let skipped = match self {
+            Method::Rest => Resource::BulkOrders,
+            Method::Bulk => Resource::Orders,
+        };
+
+        Resource::ALL
+            .into_iter()
+            .filter(|&resource| resource != skipped)
+            .collect()
+    }
+}
+
+/// What a step of a job got done.
+pub enum Step {
+    /// A page was stored. Resume from the cursor, or `None` once the resource is done.
+    Stored(Option<String>),
+    /// Shopify is still preparing the data. Resume from the cursor in a later run.
+    Pending(String),
+    /// Nothing to do until a later run.
+    Idle,
+}
+
+/// Queues the backfill of every resource, restarting it if the store already has one.
+pub async fn queue(db: &D1Database, shop: &str) -> Result<()> {
+    let now = time::OffsetDateTime::now_utc().unix_timestamp();
+
+    let method = repo::backfill_method(db, shop)
+        .await?
+        .as_deref()
+        .and_then(Method::from_str)
+        .unwrap_or(Method::Rest);
+
+    Ok(repo::queue_backfill_jobs(db, shop, &method.resources(), now).await?)
+}
+
+/// Advances the pending jobs of every active store. Called by the backfill cron.
+pub async fn run_backfills(env: &Env) -> Result<()> {
+    let db = env.d1(DB_BINDING)?;
+
+    let mut jobs = repo::pending_backfill_jobs(&db).await?;
+    jobs.sort_by_key(|job| {
+        (
+            job.store_name.clone(),
+            Resource::from_str(&job.resource).map(|resource| resource as u8),
+        )
+    });
+
+    // The store whose next job has waited longest goes first, so stores take turns at
+    // the run's pages
+    let mut stores: Vec<_> = jobs.chunk_by(|a, b| a.store_name == b.store_name).collect();
+    stores.sort_by_key(|store_jobs| store_jobs[0].updated_at);
+
+    let mut pages_left = PAGES_PER_RUN;
+    for store_jobs in stores {
+        if pages_left == 0 {
+            break;
+        }
+
+        let shop = &store_jobs[0].store_name;
+        let now = time::OffsetDateTime::now_utc().unix_timestamp();
+
+        // A run still going from an earlier cron has the store, this one leaves it be
+        if !repo::claim_backfill(&db, shop, now, now + LEASE_SECS).await? {
+            continue;
+        }
+
+        let result = run_store_backfill(&db, store_jobs, &mut pages_left).await;
+        repo::release_backfill(&db, shop).await?;
+
+        if let Err(e) = result {
+            worker::console_error!("Backfill for {shop} failed: {e}");
+        }
+    }
+
+    Ok(())
+}
+
+/// Works through a store's jobs in order until the run is out of pages or a job can't go
+/// on yet.
+async fn run_store_backfill(
+    db: &D1Database,
+    jobs: &[repo::BackfillJob],
+    pages_left: &mut usize,
+) -> Result<()> {
+    for job in jobs {
+        // A store's jobs run one after the other, as the later ones read what the
+        // earlier ones stored
+        if *pages_left == 0 {
+            return Ok(());
+        }
+
+        let resource = match Resource::from_str(&job.resource) {
+            Some(resource) => resource,
+            None => continue,
+        };
+        let token = Token {
+            access_token: job.access_token.clone(),
+        };
+
+        let mut cursor = job.cursor.clone();
+        loop {
+            let now = time::OffsetDateTime::now_utc().unix_timestamp();
+
+            match next_page(
+                db,
+                &token,
+                &job.store_name,
+                resource,
+                cursor.as_deref(),
+                job.updated_at,
+            )
+            .await
+            {
+                Ok(Step::Stored(next)) => {
+                    *pages_left -= 1;
+
+                    repo::checkpoint_backfill_job(
+                        db,
+                        &job.store_name,
+                        resource,
+                        next.as_deref(),
+                        now,
+                    )
+                    .await?;
+
+                    match next {
+                        Some(next) => cursor = Some(next),
+                        None => break,
+                    }
+                }
+                // The jobs after it have to wait for it too
+                Ok(Step::Pending(next)) => {
+                    repo::set_backfill_cursor(db, &job.store_name, resource, Some(&next), now)
+                        .await?;
+
+                    return Ok(());
+                }
+                Ok(Step::Idle) => return Ok(()),
+                Err(e) => {
+                    worker::console_error!(
+                        "Backfill of {} for {} failed: {e}",
+                        resource.as_str(),
+                        job.store_name
+                    );
+
+                    let status = if job.attempts + 1 >= MAX_ATTEMPTS {
+                        JobStatus::Failed
+                    } else {
+                        JobStatus::Pending
+                    };
+                    repo::record_backfill_failure(
+                        db,
+                        &job.store_name,
+                        resource,
+                        &e.to_string(),
+                        status,
+                        now,
+                    )
+                    .await?;
+
+                    return Ok(());
+                }
+            }
+
+            if *pages_left == 0 {
+                break;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Stores the page `cursor` points at, or the first page without one.
+///
+/// Everything stored is upserted, so a page that is stored again because its
+/// checkpoint didn't make it is harmless.
+async fn next_page(
+    db: &D1Database,
+    token: &Token,
+    shop: &str,
+    resource: Resource,
+    cursor: Option<&str>,
+    updated_at: i64,
+) -> Result<Step> {
+    // The Shopify-paged resources resume from the url of their next page, which carries
+    // the `page_info` cursor
+    let url = cursor.map(str::to_owned);
+
+    let next = match resource {
+        Resource::Customers => {
+            let url = url.unwrap_or_else(|| Customers::first_page_url(shop));
+            let (page, next) = Customers::fetch_page(token, &url).await?;
+            page.insert_in_db(db, shop).await?;
+
+            next
+        }
+        Resource::Products => {
+            let url = url.unwrap_or_else(|| Products::first_page_url(shop));
+            let (page, next) = Products::fetch_page(token, &url).await?;
+            page.insert_in_db(db, shop).await?;
+
+            next
+        }
+        Resource::Orders => {
+            let url = url.unwrap_or_else(|| Orders::first_page_url(shop));
+            let (page, next) = Orders::fetch_page(token, &url).await?;
+            page.insert_in_db(db, shop).await?;
+
+            next
+        }
+        Resource::BulkOrders => {
+            return bulk::next_batch(db, token, shop, cursor, updated_at).await;
+        }
+        Resource::Payouts => {
+            let url = url.unwrap_or_else(|| payout::first_page_url(shop, IDS_PER_PAGE));
+            let (payouts, next) = match payout::fetch_page(token, &url).await? {
+                Some(page) => page,
+                // Left for a reinstall to grant, which queues the backfill again
+                None => {
+                    worker::console_log!(
+                        "{shop} hasn't granted read_shopify_payments_payouts, skipping payouts"
+                    );
+                    return Ok(Step::Stored(None));
+                }
+            };
+
+            if cursor.is_none() {
+                let now = time::OffsetDateTime::now_utc().unix_timestamp();
+                repo::start_payout_sync(db, shop, now).await?;
+            }
+
+            for payout in &payouts {
+                let transactions =
+                    payout::fetch_balance_transactions(token, shop, payout.id).await?;
+                repo::upsert_payout(db, payout, &transactions, shop).await?;
+            }
+
+            next
+        }
+        Resource::Disputes => {
+            let url = url.unwrap_or_else(|| Disputes::first_page_url(shop));
+            let (page, next) = Disputes::fetch_page(token, &url).await?;
+            page.insert_in_db(db, shop).await?;
+
+            next
+        }
+        // The per-id resources resume after the last id they got through
+        Resource::Transactions => {
+            let ids = repo::order_ids_after(db, shop, last_id(cursor), IDS_PER_PAGE).await?;
+            for &order_id in &ids {
+                Transactions::fetch(token, shop, order_id)
+                    .await?
+                    .insert_in_db(db, shop)
+                    .await?;
+            }
+
+            next_id_cursor(&ids)
+        }
+        Resource::DisputeEvidence => {
+            let ids = repo::dispute_ids_after(db, shop, last_id(cursor), IDS_PER_PAGE).await?;
+            for &dispute_id in &ids {
+                DisputeEvidence::refresh(db, token, shop, dispute_id).await?;
+            }
+
+            next_id_cursor(&ids)
+        }
+    };
+
+    Ok(Step::Stored(next))
+}
+
+fn last_id(cursor: Option<&str>) -> ShopifyId {
+    cursor
+        .and_then(|cursor| cursor.parse().ok())
+        .unwrap_or(ShopifyId(0))
+}
+
+/// A short page means there was nothing after it.
+fn next_id_cursor(ids: &[ShopifyId]) -> Option<String> {
+    match ids.last() {
+        Some(last) if ids.len() == IDS_PER_PAGE as usize => Some(last.to_string()),
+        _ => None,
+    }
+}
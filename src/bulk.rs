@@ -0,0 +1,792 @@
+//! Backfills orders, their line items and customers through a GraphQL bulk operation,
+//! for stores with too much history to page through the REST API. Shopify runs the
+//! query on its side and hands out a JSONL file of the results, in which every order
+//! is a line followed by a line per line item carrying the order's id as `__parentId`.
+//!
+//! The job's cursor tracks the operation: empty before it is submitted, its id while it
+//! runs, and its id followed by the byte offset reached in the file while the result is
+//! stored. The file is read a range at a time so a run never holds all of it.
+
+use std::time::Duration;
+
+use worker::{
+    D1Database, Delay, Fetch, Headers, Method, Request, RequestInit, Response, RouteContext,
+};
+
+use crate::{
+    backfill::{Resource, Step},
+    client,
+    customer::Customer,
+    fetch,
+    order::{LineItem, Order},
+    repo,
+    shopify_id::ShopifyId,
+    store_param,
+    webhook::{self, EventStatus},
+    Error, Result, Token, DB_BINDING,
+};
+
+/// How often a running operation is polled, in case its `bulk_operations/finish`
+/// webhook doesn't arrive.
+const POLL_SECONDS: i64 = 5 * 60;
+/// Bytes of the result read per run. An order and its line items have to fit.
+const CHUNK_BYTES: usize = 4 * 1024 * 1024;
+/// Orders stored per run, like a page of the REST backfill.
+const ORDERS_PER_BATCH: usize = 250;
+/// Times a GraphQL call is sent while Shopify answers it as `THROTTLED`.
+const GRAPHQL_ATTEMPTS: u32 = 3;
+
+const ORDERS_QUERY: &str = r#"{
+  orders(query: "financial_status:paid") {
+    edges {
+      node {
+        id
+        name
+        createdAt
+        processedAt
+        totalPriceSet { shopMoney { amount } }
+        subtotalPriceSet { shopMoney { amount } }
+        totalTaxSet { shopMoney { amount } }
+        totalDiscountsSet { shopMoney { amount } }
+        currencyCode
+        presentmentCurrencyCode
+        displayFinancialStatus
+        displayFulfillmentStatus
+        cancelledAt
+        cancelReason
+        tags
+        customer {
+          id
+          email
+          firstName
+          lastName
+          phone
+          emailMarketingConsent { marketingState }
+          amountSpent { amount }
+          numberOfOrders
+          createdAt
+        }
+        lineItems {
+          edges {
+            node {
+              id
+              title
+              product { id }
+              variant { id }
+              sku
+              vendor
+              quantity
+              originalUnitPriceSet { shopMoney { amount } }
+              totalDiscountSet { shopMoney { amount } }
+              taxable
+            }
+          }
+        }
+      }
+    }
+  }
+}"#;
+
+/// Where a bulk backfill is at, as saved in its cursor.
+enum Cursor<'a> {
+    Unsubmitted,
+    Running { id: &'a str },
+    Storing { id: &'a str, offset: usize },
+}
+
+impl<'a> Cursor<'a> {
+    fn parse(cursor: Option<&'a str>) -> Self {
+        let cursor = match cursor {
+            Some(cursor) => cursor,
+            None => return Cursor::Unsubmitted,
+        };
+
+        match cursor.split_once(' ') {
+            Some((id, offset)) => Cursor::Storing {
+                id,
+                offset: offset.parse().unwrap_or_default(),
+            },
+            None => Cursor::Running { id: cursor },
+        }
+    }
+}
+
+fn storing_cursor(id: &str, offset: usize) -> String {
+    format!("{id} {offset}")
+}
+
+/// Advances the bulk backfill of the store by one step. `updated_at` is when its
+/// cursor was last saved, which paces the polling of a running operation.
+pub async fn next_batch(
+    db: &D1Database,
+    token: &Token,
+    shop: &str,
+    cursor: Option<&str>,
+    updated_at: i64,
+) -> Result<Step> {
+    let now = time::OffsetDateTime::now_utc().unix_timestamp();
+
+    let (id, offset) = match Cursor::parse(cursor) {
+        Cursor::Unsubmitted => return Ok(Step::Pending(submit(token, shop).await?)),
+        Cursor::Running { id } => {
+            if now - updated_at < POLL_SECONDS {
+                return Ok(Step::Idle);
+            }
+
+            (id, None)
+        }
+        Cursor::Storing { id, offset } => (id, Some(offset)),
+    };
+
+    let operation = poll(token, shop, id).await?;
+    match operation.status.as_str() {
+        "COMPLETED" => {}
+        "CREATED" | "RUNNING" => return Ok(Step::Pending(id.to_string())),
+        status => {
+            // Starts over with a new operation on the next run
+            repo::set_backfill_cursor(db, shop, Resource::BulkOrders, None, now).await?;
+
+            return Err(Error::Upstream(format!(
+                "Bulk operation {id} ended as {status}: {}",
+                operation.error_code.as_deref().unwrap_or("no error code")
+            )));
+        }
+    }
+
+    let url = match operation.url {
+        Some(url) => url,
+        // Nothing matched the query
+        None => return Ok(Step::Stored(None)),
+    };
+    let offset = offset.unwrap_or_default();
+
+    let (chunk, eof) = download_chunk(&url, offset).await?;
+    let (orders, consumed) = parse_orders(&chunk, eof)?;
+
+    if !eof && consumed == 0 {
+        return Err(Error::Upstream(format!(
+            "An order at byte {offset} of the result of bulk operation {id} doesn't fit in {CHUNK_BYTES} bytes"
+        )));
+    }
+
+    repo::upsert_orders(db, &orders, shop).await?;
+
+    if eof && consumed == chunk.len() {
+        Ok(Step::Stored(None))
+    } else {
+        Ok(Step::Stored(Some(storing_cursor(id, offset + consumed))))
+    }
+}
+
+#[derive(serde::Deserialize)]
+struct GraphqlResponse<T> {
+    data: Option<T>,
+    #[serde(default)]
+    errors: Vec<GraphqlError>,
+    extensions: Option<GraphqlExtensions>,
+}
+
+#[derive(serde::Deserialize)]
+struct GraphqlError {
+    message: String,
+    extensions: Option<GraphqlErrorExtensions>,
+}
+
+#[derive(serde::Deserialize)]
+struct GraphqlErrorExtensions {
+    code: Option<String>,
+}
+
+#[derive(serde::Deserialize)]
+struct GraphqlExtensions {
+    cost: Option<QueryCost>,
+}
+
+#[derive(serde::Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct QueryCost {
+    requested_query_cost: f64,
+    throttle_status: ThrottleStatus,
+}
+
+#[derive(serde::Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct ThrottleStatus {
+    currently_available: f64,
+    /// Points restored per second.
+    restore_rate: f64,
+}
+
+impl QueryCost {
+    /// How long until enough points are restored to run the query again.
+    fn wait_millis(&self) -> u64 {
+        let missing = self.requested_query_cost - self.throttle_status.currently_available;
+
+        if missing > 0.0 && self.throttle_status.restore_rate > 0.0 {
+            (missing / self.throttle_status.restore_rate * 1000.0).ceil() as u64
+        } else {
+            0
+        }
+    }
+}
+
+#[derive(serde::Deserialize)]
+struct UserError {
+    message: String,
+}
+
+/// Sends a GraphQL call. Shopify throttles these by query cost rather than per call,
+/// answering with a `THROTTLED` error, in which case the call wasn't run and is sent
+/// again once enough points are restored.
+async fn graphql<T: serde::de::DeserializeOwned>(
+    token: &Token,
+    shop: &str,
+    query: &str,
+    variables: serde_json::Value,
+) -> Result<T> {
+    let payload = serde_json::json!({ "query": query, "variables": variables }).to_string();
+
+    let mut attempt = 0;
+    loop {
+        attempt += 1;
+
+        let mut resp = fetch(
+            token,
+            Request::new_with_init(
+                &format!("https://{shop}/admin/api/2023-01/graphql.json"),
+                &RequestInit {
+                    body: Some(payload.as_str().into()),
+                    method: Method::Post,
+                    headers: {
+                        let mut headers = Headers::default();
+                        headers.append("Content-Type", "application/json")?;
+
+                        headers
+                    },
+                    ..Default::default()
+                },
+            )?,
+        )
+        .await?;
+
+        let body: GraphqlResponse<T> = client::json(&mut resp).await?;
+
+        let throttled = body.errors.iter().any(|error| {
+            error
+                .extensions
+                .as_ref()
+                .and_then(|extensions| extensions.code.as_deref())
+                == Some("THROTTLED")
+        });
+        if throttled && attempt < GRAPHQL_ATTEMPTS {
+            let delay = body
+                .extensions
+                .and_then(|extensions| extensions.cost)
+                .map(|cost| cost.wait_millis())
+                .unwrap_or(1_000);
+
+            worker::console_log!("GraphQL call to {shop} was throttled, retrying in {delay}ms");
+            Delay::from(Duration::from_millis(delay)).await;
+            continue;
+        }
+
+        return match body.data {
+            Some(data) if body.errors.is_empty() => Ok(data),
+            _ => Err(Error::Upstream(
+                body.errors
+                    .into_iter()
+                    .map(|error| error.message)
+                    .collect::<Vec<_>>()
+                    .join(", "),
+            )),
+        };
+    }
+}
+
+/// Starts the bulk query and returns the id of the operation.
+async fn submit(token: &Token, shop: &str) -> Result<String> {
+    #[derive(serde::Deserialize)]
+    #[serde(rename_all = "camelCase")]
+    struct Data {
+        bulk_operation_run_query: RunQuery,
+    }
+
+    #[derive(serde::Deserialize)]
+    #[serde(rename_all = "camelCase")]
+    struct RunQuery {
+        bulk_operation: Option<Operation>,
+        user_errors: Vec<UserError>,
+    }
+
+    #[derive(serde::Deserialize)]
+    struct Operation {
+        id: String,
+    }
+
+    let data: Data = graphql(
+        token,
+        shop,
+        "mutation ($query: String!) { bulkOperationRunQuery(query: $query) { bulkOperation { id } userErrors { message } } }",
+        serde_json::json!({ "query": ORDERS_QUERY }),
+    )
+    .await?;
+
+    let run = data.bulk_operation_run_query;
+    match run.bulk_operation {
+        Some(operation) if run.user_errors.is_empty() => Ok(operation.id),
+        // e.g. when another bulk query of the app is still running for the store
+        _ => Err(Error::Upstream(format!(
+            "Failed to start bulk operation: {}",
+            run.user_errors
+                .into_iter()
+                .map(|error| error.message)
+                .collect::<Vec<_>>()
+                .join(", ")
+        ))),
+    }
+}
+
+#[derive(serde::Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Operation {
+    /// `CREATED`, `RUNNING`, `COMPLETED`, `CANCELING`, `CANCELED`, `EXPIRED` or `FAILED`.
+    status: String,
+    error_code: Option<String>,
+    /// Where the JSONL result can be downloaded once completed. `None` if nothing matched.
+    url: Option<String>,
+}
+
+async fn poll(token: &Token, shop: &str, id: &str) -> Result<Operation> {
+    #[derive(serde::Deserialize)]
+    struct Data {
+        node: Option<Operation>,
+    }
+
+    let data: Data = graphql(
+        token,
+        shop,
+        "query ($id: ID!) { node(id: $id) { ... on BulkOperation { status errorCode url } } }",
+        serde_json::json!({ "id": id }),
+    )
+    .await?;
+
+    data.node
+        .ok_or_else(|| Error::Upstream(format!("Bulk operation {id} wasn't found")))
+}
+
+/// Reads `CHUNK_BYTES` of the result from `offset`. Returns whether the end of the file
+/// was reached.
+async fn download_chunk(url: &str, offset: usize) -> Result<(Vec<u8>, bool)> {
+    // The url is signed, so it's fetched without the store's access token
+    let mut resp = Fetch::Request(Request::new_with_init(
+        url,
+        &RequestInit {
+            method: Method::Get,
+            headers: {
+                let mut headers = Headers::default();
+                headers.append(
+                    "Range",
+                    &format!("bytes={offset}-{}", offset + CHUNK_BYTES - 1),
+                )?;
+
+                headers
+            },
+            ..Default::default()
+        },
+    )?)
+    .send()
+    .await?;
+
+    match resp.status_code() {
+        206 => {
+            let chunk = resp.bytes().await?;
+            let eof = chunk.len() < CHUNK_BYTES;
+
+            Ok((chunk, eof))
+        }
+        // The range was ignored. Reading on would hold the whole file, and download it
+        // again for every batch
+        200 => Err(Error::Upstream(format!(
+            "Bulk operation result ignored the range from byte {offset}"
+        ))),
+        // The offset is the end of the file
+        416 => Ok((Vec::new(), true)),
+        status => Err(Error::Upstream(format!(
+            "Bulk operation result responded with {status}"
+        ))),
+    }
+}
+
+/// Groups the lines of the chunk into orders. An order is only complete once the next
+/// one starts or the file ends, so an order cut off by the end of the chunk is left for
+/// the next run. Returns the orders and how many bytes of the chunk they took up.
+fn parse_orders(chunk: &[u8], eof: bool) -> Result<(Vec<Order>, usize)> {
+    let malformed =
+        |e: serde_json::Error| Error::Upstream(format!("Malformed bulk operation result: {e}"));
+
+    let mut orders = Vec::new();
+    let mut current: Option<Order> = None;
+    let mut consumed = 0;
+    let mut start = 0;
+
+    while start < chunk.len() {
+        let end = match chunk[start..].iter().position(|&b| b == b'\n') {
+            Some(newline) => start + newline + 1,
+            None if eof => chunk.len(),
+            None => break,
+        };
+
+        let line = &chunk[start..end];
+        if !line.iter().all(u8::is_ascii_whitespace) {
+            let value: serde_json::Value = serde_json::from_slice(line).map_err(malformed)?;
+
+            if value.get("__parentId").is_some() {
+                let line_item: BulkLineItem = serde_json::from_value(value).map_err(malformed)?;
+
+                match current.as_mut() {
+                    Some(order) if order.id == line_item.parent_id => {
+                        order.line_items.push(line_item.into())
+                    }
+                    // Children always directly follow their order
+                    _ => {
+                        return Err(Error::Upstream(format!(
+                            "Line item {} came apart from its order",
+                            line_item.id
+                        )))
+                    }
+                }
+            } else {
+                if let Some(order) = current.take() {
+                    orders.push(order);
+                    consumed = start;
+
+                    if orders.len() == ORDERS_PER_BATCH {
+                        return Ok((orders, consumed));
+                    }
+                }
+
+                let order: BulkOrder = serde_json::from_value(value).map_err(malformed)?;
+                current = Some(order.into());
+            }
+        }
+
+        start = end;
+    }
+
+    if eof {
+        orders.extend(current);
+        consumed = chunk.len();
+    }
+
+    Ok((orders, consumed))
+}
+
+#[derive(serde::Deserialize)]
+struct Money {
+    amount: String,
+}
+
+#[derive(serde::Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct MoneyBag {
+    shop_money: Money,
+}
+
+impl MoneyBag {
+    fn amount(self) -> String {
+        self.shop_money.amount
+    }
+}
+
+#[derive(serde::Deserialize)]
+struct Node {
+    id: ShopifyId,
+}
+
+#[derive(serde::Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct MarketingConsent {
+    marketing_state: String,
+}
+
+#[derive(serde::Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct BulkCustomer {
+    id: ShopifyId,
+    email: Option<String>,
+    first_name: Option<String>,
+    last_name: Option<String>,
+    phone: Option<String>,
+    email_marketing_consent: Option<MarketingConsent>,
+    amount_spent: Option<Money>,
+    /// An `UnsignedInt64`, which GraphQL sends as a string.
+    number_of_orders: Option<String>,
+    created_at: Option<String>,
+}
+
+impl From<BulkCustomer> for Customer {
+    fn from(customer: BulkCustomer) -> Self {
+        Customer {
+            id: customer.id,
+            email: customer.email,
+            first_name: customer.first_name,
+            last_name: customer.last_name,
+            phone: customer.phone,
+            accepts_marketing: customer
+                .email_marketing_consent
+                .map(|consent| consent.marketing_state == "SUBSCRIBED"),
+            total_spent: customer.amount_spent.map(|money| money.amount),
+            orders_count: customer
+                .number_of_orders
+                .and_then(|count| count.parse().ok()),
+            created_at: customer.created_at,
+        }
+    }
+}
+
+#[derive(serde::Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct BulkLineItem {
+    #[serde(rename = "__parentId")]
+    parent_id: ShopifyId,
+    id: ShopifyId,
+    title: String,
+    product: Option<Node>,
+    variant: Option<Node>,
+    sku: Option<String>,
+    vendor: Option<String>,
+    quantity: u64,
+    original_unit_price_set: MoneyBag,
+    total_discount_set: MoneyBag,
+    taxable: bool,
+}
+
+impl From<BulkLineItem> for LineItem {
+    fn from(line_item: BulkLineItem) -> Self {
+        LineItem {
+            id: line_item.id,
+            title: line_item.title,
+            product_id: line_item.product.map(|product| product.id),
+            variant_id: line_item.variant.map(|variant| variant.id),
+            sku: line_item.sku,
+            vendor: line_item.vendor,
+            quantity: line_item.quantity,
+            price: line_item.original_unit_price_set.amount(),
+            total_discount: line_item.total_discount_set.amount(),
+            taxable: line_item.taxable,
+        }
+    }
+}
+
+/// GraphQL has no order number, checkout token or source name on an order. Bulk imported
+/// orders leave them out, and storing one keeps whatever REST or a webhook stored for them
+/// before.
+#[derive(serde::Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct BulkOrder {
+    id: ShopifyId,
+    name: String,
+    created_at: String,
+    processed_at: Option<String>,
+    total_price_set: MoneyBag,
+    subtotal_price_set: Option<MoneyBag>,
+    total_tax_set: Option<MoneyBag>,
+    total_discounts_set: Option<MoneyBag>,
+    currency_code: String,
+    presentment_currency_code: Option<String>,
+    /// Upper case, e.g. `PARTIALLY_REFUNDED` for the REST API's `partially_refunded`.
+    display_financial_status: Option<String>,
+    display_fulfillment_status: Option<String>,
+    cancelled_at: Option<String>,
+    cancel_reason: Option<String>,
+    #[serde(default)]
+    tags: Vec<String>,
+    customer: Option<BulkCustomer>,
+}
+
+impl From<BulkOrder> for Order {
+    fn from(order: BulkOrder) -> Self {
+        Order {
+            id: order.id,
+            order_number: None,
+            name: order.name,
+            customer: order.customer.map(Customer::from),
+            line_items: Vec::new(),
+            created_at: order.created_at,
+            processed_at: order.processed_at,
+            total_price: order.total_price_set.amount(),
+            subtotal_price: order.subtotal_price_set.map(MoneyBag::amount),
+            total_tax: order.total_tax_set.map(MoneyBag::amount),
+            total_discounts: order.total_discounts_set.map(MoneyBag::amount),
+            currency: order.currency_code,
+            presentment_currency: order.presentment_currency_code,
+            financial_status: order
+                .display_financial_status
+                .map(|status| status.to_lowercase()),
+            // The REST API only has these three, and no status for an unfulfilled order
+            fulfillment_status: order.display_fulfillment_status.and_then(|status| {
+                match status.as_str() {
+                    "FULFILLED" => Some("fulfilled".to_string()),
+                    "PARTIALLY_FULFILLED" => Some("partial".to_string()),
+                    "RESTOCKED" => Some("restocked".to_string()),
+                    _ => None,
+                }
+            }),
+            cancelled_at: order.cancelled_at,
+            cancel_reason: order.cancel_reason.map(|reason| reason.to_lowercase()),
+            tags: order.tags.join(", "),
+            source_name: None,
+            checkout_token: None,
+            fulfillments: Vec::new(),
+        }
+    }
+}
+
+/// Handles `bulk_operations/finish`, so a completed operation is stored on the next run
+/// instead of once it's next polled.
+pub async fn handle_finish_webhook<'a, D: 'a>(
+    mut req: Request,
+    ctx: RouteContext<D>,
+) -> Result<Response> {
+    #[derive(serde::Deserialize)]
+    struct ReqBody {
+        admin_graphql_api_id: String,
+        /// Lower case, unlike in GraphQL.
+        status: String,
+        error_code: Option<String>,
+    }
+
+    let db = ctx.env.d1(DB_BINDING)?;
+
+    let (event, body) = match webhook::receive::<ReqBody>(&mut req, &ctx.env, &db).await? {
+        Ok(received) => received,
+        Err(resp) => return Ok(resp),
+    };
+    let shop = store_param(&ctx)?;
+
+    let id = body.admin_graphql_api_id;
+    if body.status == "completed" {
+        let now = time::OffsetDateTime::now_utc().unix_timestamp();
+        repo::replace_backfill_cursor(
+            &db,
+            shop,
+            Resource::BulkOrders,
+            &id,
+            &storing_cursor(&id, 0),
+            now,
+        )
+        .await?;
+    } else {
+        // Left for the next poll, which restarts the backfill
+        worker::console_error!(
+            "Bulk operation {id} for {shop} ended as {}: {}",
+            body.status,
+            body.error_code.as_deref().unwrap_or("no error code")
+        );
+    }
+
+    event.finish(&db, EventStatus::Processed).await?;
+
+    Ok(Response::ok("ok")?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn order_line(id: u64) -> String {
+        format!(
+            r#"{{"id":"gid://shopify/Order/{id}","name":"Order {id}","createdAt":"2024-01-01T00:00:00Z","totalPriceSet":{{"shopMoney":{{"amount":"10.00"}}}},"currencyCode":"USD"}}"#
+        ) + "\n"
+    }
+
+    fn line_item_line(order_id: u64, id: u64) -> String {
+        format!(
+            r#"{{"__parentId":"gid://shopify/Order/{order_id}","id":"gid://shopify/LineItem/{id}","title":"Hat","quantity":1,"originalUnitPriceSet":{{"shopMoney":{{"amount":"10.00"}}}},"totalDiscountSet":{{"shopMoney":{{"amount":"0.00"}}}},"taxable":true}}"#
+        ) + "\n"
+    }
+
+    #[test]
+    fn parses_orders_with_their_line_items() {
+        let chunk =
+            order_line(1) + &line_item_line(1, 11) + &line_item_line(1, 12) + &order_line(2);
+
+        let (orders, consumed) = parse_orders(chunk.as_bytes(), true).unwrap();
+
+        assert_eq!(orders.len(), 2);
+        assert_eq!(orders[0].id, ShopifyId(1));
+        assert_eq!(orders[0].line_items.len(), 2);
+        assert_eq!(orders[1].id, ShopifyId(2));
+        assert!(orders[1].line_items.is_empty());
+        assert_eq!(consumed, chunk.len());
+    }
+
+    #[test]
+    fn leaves_an_order_cut_off_by_the_chunk_for_the_next_run() {
+        let first = order_line(1) + &line_item_line(1, 11);
+        let second = order_line(2) + &line_item_line(2, 21);
+        // Ends part way through the second order's line item
+        let chunk = first.clone() + &second[..second.len() - 10];
+
+        let (orders, consumed) = parse_orders(chunk.as_bytes(), false).unwrap();
+
+        assert_eq!(orders.len(), 1);
+        assert_eq!(orders[0].id, ShopifyId(1));
+        assert_eq!(orders[0].line_items.len(), 1);
+        assert_eq!(consumed, first.len());
+    }
+
+    #[test]
+    fn keeps_the_last_whole_order_until_the_file_ends() {
+        // More line items of the second order could still follow in the next chunk
+        let first = order_line(1);
+        let chunk = first.clone() + &order_line(2) + &line_item_line(2, 21);
+
+        let (orders, consumed) = parse_orders(chunk.as_bytes(), false).unwrap();
+
+        assert_eq!(orders.len(), 1);
+        assert_eq!(consumed, first.len());
+    }
+
+    #[test]
+    fn stops_at_a_full_batch() {
+        let lines: Vec<String> = (1..=ORDERS_PER_BATCH as u64 + 2).map(order_line).collect();
+        let chunk = lines.concat();
+
+        let (orders, consumed) = parse_orders(chunk.as_bytes(), true).unwrap();
+
+        assert_eq!(orders.len(), ORDERS_PER_BATCH);
+        assert_eq!(
+            orders.last().unwrap().id,
+            ShopifyId(ORDERS_PER_BATCH as u64)
+        );
+        assert_eq!(consumed, lines[..ORDERS_PER_BATCH].concat().len());
+    }
+
+    #[test]
+    fn waits_for_the_missing_query_points() {
+        let cost: QueryCost = serde_json::from_str(
+            r#"{"requestedQueryCost":101,"actualQueryCost":null,"throttleStatus":{"maximumAvailable":1000,"currentlyAvailable":1,"restoreRate":50}}"#,
+        )
+        .unwrap();
+
+        assert_eq!(cost.wait_millis(), 2_000);
+    }
+
+    #[test]
+    fn doesnt_wait_with_enough_query_points() {
+        let cost: QueryCost = serde_json::from_str(
+            r#"{"requestedQueryCost":10,"throttleStatus":{"maximumAvailable":1000,"currentlyAvailable":500,"restoreRate":50}}"#,
+        )
+        .unwrap();
+
+        assert_eq!(cost.wait_millis(), 0);
+    }
+
+    #[test]
+    fn rejects_a_line_item_apart_from_its_order() {
+        let chunk = order_line(1) + &line_item_line(2, 21);
+
+        assert!(parse_orders(chunk.as_bytes(), true).is_err());
+    }
+}
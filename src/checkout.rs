@@ -0,0 +1,36 @@
+use crate::{customer::Customer, shopify_id::ShopifyId};
+
+#[derive(Debug, serde::Deserialize)]
+pub struct CheckoutLineItem {
+    /// Identifies the line within the checkout. Checkout line items have no id.
+    pub(crate) key: Option<String>,
+    pub(crate) title: String,
+    pub(crate) variant_title: Option<String>,
+    pub(crate) product_id: Option<ShopifyId>,
+    pub(crate) variant_id: Option<ShopifyId>,
+    pub(crate) sku: Option<String>,
+    pub(crate) vendor: Option<String>,
+    pub(crate) quantity: u64,
+    pub(crate) price: String,
+    /// `price` times `quantity`.
+    pub(crate) line_price: Option<String>,
+}
+
+/// A checkout that hasn't been turned into an order, as listed by `checkouts.json`.
+#[derive(Debug, serde::Deserialize)]
+pub struct Checkout {
+    pub(crate) id: ShopifyId,
+    /// Orders placed through this checkout carry it as their `checkout_token`.
+    pub(crate) token: String,
+    pub(crate) abandoned_checkout_url: String,
+    pub(crate) customer: Option<Customer>,
+    /// Entered at checkout, so guests that never became a customer have it too.
+    pub(crate) email: Option<String>,
+    pub(crate) created_at: String,
+    pub(crate) updated_at: Option<String>,
+    pub(crate) completed_at: Option<String>,
+    pub(crate) total_price: Option<String>,
+    pub(crate) currency: Option<String>,
+    #[serde(default)]
+    pub(crate) line_items: Vec<CheckoutLineItem>,
+}
@@ -0,0 +1,271 @@
+//! Every call to the Admin API goes through [`fetch`], which keeps each shop within
+//! its REST rate limit. Shopify meters calls with a leaky bucket per shop and reports
+//! how full it is in `X-Shopify-Shop-Api-Call-Limit`, e.g. `32/40`. Calls wait for the
+//! bucket to drain when it is full, and throttled (429) calls are retried with a jittered
+//! exponential backoff, or after `Retry-After` when Shopify sends one. Failed (5xx) calls
+//! are only retried for reads, as a failed write may still have been applied.
+//!
+//! GraphQL calls are throttled by query cost instead, which Shopify answers with a 200
+//! and a `THROTTLED` error. [`crate::bulk`] waits those out itself.
+
+use std::{cell::RefCell, collections::HashMap, fmt, time::Duration};
+
+use worker::{Delay, Fetch, Method, Request, Response};
+
+use crate::{Error, Token};
+
+const MAX_ATTEMPTS: u32 = 5;
+const BASE_BACKOFF_MILLIS: u64 = 500;
+const MAX_BACKOFF_MILLIS: u64 = 30_000;
+/// How many calls the bucket of a standard plan drains per second. Plus plans drain
+/// faster, which the header doesn't tell, so they are just paced conservatively.
+const LEAK_PER_SECOND: f64 = 2.0;
+
+#[derive(Debug)]
+pub enum FetchError {
+    /// Shopify kept throttling or failing the call.
+    RetriesExhausted {
+        url: String,
+        status: u16,
+        attempts: u32,
+    },
+    Worker(worker::Error),
+}
+
+impl fmt::Display for FetchError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FetchError::RetriesExhausted {
+                url,
+                status,
+                attempts,
+            } => write!(
+                f,
+                "{url} still responded with {status} after {attempts} attempts"
+            ),
+            FetchError::Worker(e) => e.fmt(f),
+        }
+    }
+}
+
+impl std::error::Error for FetchError {}
+
+impl From<worker::Error> for FetchError {
+    fn from(e: worker::Error) -> Self {
+        FetchError::Worker(e)
+    }
+}
+
+/// The last reading of a shop's bucket.
+struct Bucket {
+    used: f64,
+    capacity: f64,
+    observed_at: i64,
+}
+
+impl Bucket {
+    /// Parses `X-Shopify-Shop-Api-Call-Limit`, e.g. `32/40`.
+    fn parse(header: &str, observed_at: i64) -> Option<Self> {
+        let (used, capacity) = header.split_once('/')?;
+
+        Some(Bucket {
+            used: used.trim().parse().ok()?,
+            capacity: capacity.trim().parse().ok()?,
+            observed_at,
+        })
+    }
+
+    /// How long to wait until the bucket has room for another call.
+    fn wait_millis(&self, now: i64) -> u64 {
+        let drained = (now - self.observed_at) as f64 / 1000.0 * LEAK_PER_SECOND;
+        let overflow = (self.used - drained).max(0.0) + 1.0 - self.capacity;
+
+        if overflow > 0.0 {
+            (overflow / LEAK_PER_SECOND * 1000.0).ceil() as u64
+        } else {
+            0
+        }
+    }
+}
+
+// Workers run an isolate on a single thread, and the readings only need to last as
+// long as the isolate does
+thread_local! {
+    static BUCKETS: RefCell<HashMap<String, Bucket>> = RefCell::default();
+}
+
+fn now_millis() -> i64 {
+    (time::OffsetDateTime::now_utc().unix_timestamp_nanos() / 1_000_000) as i64
+}
+
+/// A random wait of at least half the base backoff, up to a cap that doubles every attempt.
+fn backoff_millis(attempt: u32) -> u64 {
+    use rand::Rng;
+
+    let cap = BASE_BACKOFF_MILLIS
+        .saturating_mul(1 << (attempt - 1).min(16))
+        .min(MAX_BACKOFF_MILLIS);
+
+    rand::thread_rng().gen_range(BASE_BACKOFF_MILLIS / 2..=cap)
+}
+
+/// `Retry-After` in seconds, which Shopify sends as e.g. `2.0`.
+fn retry_after_millis(header: &str) -> Option<u64> {
+    header
+        .trim()
+        .parse::<f64>()
+        .ok()
+        .filter(|seconds| seconds.is_finite() && *seconds >= 0.0)
+        .map(|seconds| (seconds * 1000.0).ceil() as u64)
+}
+
+/// Reads the JSON body of a successful Admin API response.
+pub async fn json<T: serde::de::DeserializeOwned>(resp: &mut Response) -> crate::Result<T> {
+    let status = resp.status_code();
+    if !(200..300).contains(&status) {
+        return Err(Error::Upstream(format!("Shopify responded with {status}")));
+    }
+
+    resp.json()
+        .await
+        .map_err(|e| Error::Upstream(format!("Failed to parse Shopify response: {e}")))
+}
+
+/// Sends an authenticated call to the Admin API of the shop `req` is addressed to.
+pub async fn fetch(token: &Token, req: Request) -> Result<Response, FetchError> {
+    let url = req.url()?;
+    let shop = url.host_str().unwrap_or_default().to_string();
+    let idempotent = matches!(req.method(), Method::Get | Method::Head);
+
+    let mut attempt = 0;
+    loop {
+        attempt += 1;
+
+        let wait = BUCKETS.with(|buckets| {
+            buckets
+                .borrow()
+                .get(&shop)
+                .map(|bucket| bucket.wait_millis(now_millis()))
+                .unwrap_or_default()
+        });
+        if wait > 0 {
+            Delay::from(Duration::from_millis(wait)).await;
+        }
+
+        // A request can only be sent once, so every attempt sends a copy
+        let mut attempt_req = req.clone()?;
+        attempt_req
+            .headers_mut()?
+            .append("X-Shopify-Access-Token", &token.access_token)?;
+
+        let resp = Fetch::Request(attempt_req).send().await?;
+
+        if let Some(bucket) = resp
+            .headers()
+            .get("X-Shopify-Shop-Api-Call-Limit")?
+            .and_then(|header| Bucket::parse(&header, now_millis()))
+        {
+            BUCKETS.with(|buckets| buckets.borrow_mut().insert(shop.clone(), bucket));
+        }
+
+        // A throttled call wasn't applied, but a failed one may have been
+        let status = resp.status_code();
+        if status != 429 && (status < 500 || !idempotent) {
+            return Ok(resp);
+        }
+
+        if attempt >= MAX_ATTEMPTS {
+            return Err(FetchError::RetriesExhausted {
+                url: url.to_string(),
+                status,
+                attempts: attempt,
+            });
+        }
+
+        let retry_after = resp
+            .headers()
+            .get("Retry-After")?
+            .as_deref()
+            .and_then(retry_after_millis);
+        let delay = match retry_after {
+            Some(delay) if status == 429 => delay,
+            _ => backoff_millis(attempt),
+        };
+
+        worker::console_log!("{url} responded with {status}, retrying in {delay}ms");
+        Delay::from(Duration::from_millis(delay)).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_the_call_limit_header() {
+        let bucket = Bucket::parse(" 32/40 ", 1_000).unwrap();
+
+        assert_eq!(bucket.used, 32.0);
+        assert_eq!(bucket.capacity, 40.0);
+        assert_eq!(bucket.observed_at, 1_000);
+    }
+
+    #[test]
+    fn rejects_a_malformed_call_limit_header() {
+        assert!(Bucket::parse("", 0).is_none());
+        assert!(Bucket::parse("32", 0).is_none());
+        assert!(Bucket::parse("32/forty", 0).is_none());
+    }
+
+    #[test]
+    fn doesnt_wait_with_room_in_the_bucket() {
+        let bucket = Bucket::parse("39/40", 0).unwrap();
+
+        assert_eq!(bucket.wait_millis(0), 0);
+    }
+
+    #[test]
+    fn waits_for_a_full_bucket_to_drain() {
+        let bucket = Bucket::parse("40/40", 0).unwrap();
+
+        // One call has to drain, at two a second
+        assert_eq!(bucket.wait_millis(0), 500);
+        assert_eq!(bucket.wait_millis(250), 250);
+        assert_eq!(bucket.wait_millis(500), 0);
+    }
+
+    #[test]
+    fn counts_what_drained_since_the_reading() {
+        let bucket = Bucket::parse("40/40", 0).unwrap();
+
+        assert_eq!(bucket.wait_millis(60_000), 0);
+    }
+
+    #[test]
+    fn reads_retry_after_in_seconds() {
+        assert_eq!(retry_after_millis("2.0"), Some(2_000));
+        assert_eq!(retry_after_millis(" 1 "), Some(1_000));
+        assert_eq!(retry_after_millis("0.25"), Some(250));
+    }
+
+    #[test]
+    fn ignores_an_unusable_retry_after() {
+        assert_eq!(retry_after_millis(""), None);
+        assert_eq!(retry_after_millis("-1"), None);
+        assert_eq!(retry_after_millis("NaN"), None);
+        assert_eq!(retry_after_millis("Wed, 21 Oct 2015 07:28:00 GMT"), None);
+    }
+
+    #[test]
+    fn backs_off_within_a_cap_that_doubles() {
+        for attempt in 1..=20 {
+            let cap = (BASE_BACKOFF_MILLIS << (attempt - 1).min(16)).min(MAX_BACKOFF_MILLIS);
+
+            for _ in 0..100 {
+                let delay = backoff_millis(attempt);
+                assert!(delay >= BASE_BACKOFF_MILLIS / 2);
+                assert!(delay <= cap);
+            }
+        }
+    }
+}
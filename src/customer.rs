@@ -0,0 +1,66 @@
+use worker::{D1Database, Request, Response, RouteContext};
+
+use crate::{
+    client, paginate, repo,
+    shopify_id::ShopifyId,
+    store_param,
+    webhook::{self, EventStatus},
+    Result, Token, DB_BINDING,
+};
+
+/// A customer as sent in `customers/*` webhooks and embedded in orders and checkouts.
+#[derive(Debug, serde::Deserialize)]
+pub struct Customer {
+    pub(crate) id: ShopifyId,
+    pub(crate) email: Option<String>,
+    pub(crate) first_name: Option<String>,
+    pub(crate) last_name: Option<String>,
+    pub(crate) phone: Option<String>,
+    pub(crate) accepts_marketing: Option<bool>,
+    pub(crate) total_spent: Option<String>,
+    pub(crate) orders_count: Option<u64>,
+    pub(crate) created_at: Option<String>,
+}
+
+impl Customer {
+    /// Handles `customers/create` and `customers/update`, which both deliver the full customer.
+    pub async fn handle_webhook<'a, D: 'a>(
+        mut req: Request,
+        ctx: RouteContext<D>,
+    ) -> Result<Response> {
+        let db = ctx.env.d1(DB_BINDING)?;
+
+        let (event, customer) = match webhook::receive::<Customer>(&mut req, &ctx.env, &db).await? {
+            Ok(received) => received,
+            Err(resp) => return Ok(resp),
+        };
+        let shop = store_param(&ctx)?;
+
+        repo::upsert_customers(&db, std::slice::from_ref(&customer), shop).await?;
+        event.finish(&db, EventStatus::Processed).await?;
+
+        Ok(Response::ok("ok")?)
+    }
+}
+
+#[derive(serde::Deserialize)]
+pub struct Customers {
+    customers: Vec<Customer>,
+}
+
+impl Customers {
+    pub fn first_page_url(shop: &str) -> String {
+        format!("https://{shop}/admin/api/2023-01/customers.json?fields=id,email,first_name,last_name,phone,accepts_marketing,total_spent,orders_count,created_at&limit=250")
+    }
+
+    /// Fetches one page of customers and the url of the next one.
+    pub async fn fetch_page(token: &Token, url: &str) -> Result<(Self, Option<String>)> {
+        let (mut resp, next) = paginate::fetch_page(token, url).await?;
+
+        Ok((client::json(&mut resp).await?, next))
+    }
+
+    pub async fn insert_in_db(&self, db: &D1Database, shop: &str) -> Result<()> {
+        Ok(repo::upsert_customers(db, &self.customers, shop).await?)
+    }
+}
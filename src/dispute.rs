@@ -1,90 +1,181 @@
-use worker::{D1Database, Method, Request, RequestInit, Response, RouteContext};
+use worker::{D1Database, Method, Request, Response, RouteContext};
 
-use crate::{fetch, Token, DB_BINDING};
+use crate::{
+    client, fetch, paginate, repo,
+    shopify_id::ShopifyId,
+    store_param, unix_millis,
+    webhook::{self, EventStatus, WebhookEvent},
+    Result, Token, DB_BINDING,
+};
 
 #[derive(Debug, serde::Deserialize)]
 pub struct Dispute {
-    id: f64,
-    order_id: Option<f64>,
-    r#type: String,
-    amount: String,
-    currency: String,
-    reason: String,
-    status: String,
-    initiated_at: String,
-    evidence_due_by: String,
-    evidence_sent_on: Option<String>,
+    pub(crate) id: ShopifyId,
+    pub(crate) order_id: Option<ShopifyId>,
+    pub(crate) r#type: String,
+    pub(crate) amount: String,
+    pub(crate) currency: String,
+    pub(crate) reason: String,
+    pub(crate) status: String,
+    pub(crate) initiated_at: String,
+    pub(crate) evidence_due_by: String,
+    pub(crate) evidence_sent_on: Option<String>,
+    /// Not part of the REST resource today, but used to order updates if Shopify sends it.
+    #[serde(default)]
+    updated_at: Option<String>,
+}
+
+/// What the merchant has put together to fight a dispute. Shopify creates it along
+/// with the dispute and prefills it from the order.
+#[derive(Debug, serde::Deserialize)]
+pub struct DisputeEvidence {
+    pub(crate) id: ShopifyId,
+    pub(crate) access_activity_log: Option<String>,
+    pub(crate) billing_address: Option<serde_json::Value>,
+    pub(crate) shipping_address: Option<serde_json::Value>,
+    pub(crate) cancellation_policy_disclosure: Option<String>,
+    pub(crate) cancellation_rebuttal: Option<String>,
+    pub(crate) customer_email_address: Option<String>,
+    pub(crate) customer_first_name: Option<String>,
+    pub(crate) customer_last_name: Option<String>,
+    pub(crate) product_description: Option<serde_json::Value>,
+    pub(crate) refund_policy_disclosure: Option<String>,
+    pub(crate) refund_refusal_explanation: Option<String>,
+    /// Free text the merchant submits with the evidence.
+    pub(crate) uncategorized_text: Option<String>,
+    /// Shipping carriers, tracking numbers and dates.
+    #[serde(default)]
+    pub(crate) fulfillments: Vec<serde_json::Value>,
+    /// Customer communication, shipping documentation, policies and other files.
+    #[serde(default)]
+    pub(crate) dispute_file_uploads: Vec<DisputeFileUpload>,
+    pub(crate) submitted_by_merchant_on: Option<String>,
+    pub(crate) created_at: Option<String>,
+    pub(crate) updated_at: Option<String>,
+}
+
+#[derive(Debug, serde::Deserialize)]
+pub struct DisputeFileUpload {
+    pub(crate) id: ShopifyId,
+    /// e.g. `customer_communication_file` or `shipping_documentation_file`.
+    pub(crate) dispute_evidence_type: Option<String>,
+    pub(crate) file_type: Option<String>,
+    pub(crate) file_size: Option<u64>,
+    pub(crate) original_file_name: Option<String>,
+    pub(crate) url: Option<String>,
+}
+
+impl DisputeEvidence {
+    /// Returns `None` if the dispute has no evidence to fetch.
+    pub async fn fetch(token: &Token, shop: &str, dispute_id: ShopifyId) -> Result<Option<Self>> {
+        #[derive(serde::Deserialize)]
+        struct RespBody {
+            dispute_evidence: DisputeEvidence,
+        }
+
+        let mut resp = fetch(
+            token,
+            Request::new(
+                &format!("https://{shop}/admin/api/2023-01/shopify_payments/disputes/{dispute_id}/dispute_evidences.json"),
+                Method::Get,
+            )?,
+        )
+        .await?;
+
+        if resp.status_code() == 404 {
+            return Ok(None);
+        }
+
+        let body: RespBody = client::json(&mut resp).await?;
+
+        Ok(Some(body.dispute_evidence))
+    }
+
+    /// Fetches the current evidence of the dispute and replaces what is stored.
+    pub async fn refresh(
+        db: &D1Database,
+        token: &Token,
+        shop: &str,
+        dispute_id: ShopifyId,
+    ) -> Result<()> {
+        if let Some(evidence) = DisputeEvidence::fetch(token, shop, dispute_id).await? {
+            let now = time::OffsetDateTime::now_utc().unix_timestamp();
+            repo::upsert_dispute_evidence(db, dispute_id, &evidence, now).await?;
+        }
+
+        Ok(())
+    }
 }
 
 impl Dispute {
+    /// Unix milliseconds used to order the states of a dispute: its `updated_at` when
+    /// Shopify sends one, otherwise when the webhook was triggered.
+    fn version(&self, event: &WebhookEvent) -> Option<i64> {
+        self.updated_at
+            .as_deref()
+            .and_then(unix_millis)
+            .or_else(|| event.triggered_at())
+    }
+
     pub async fn handle_create_webhook<'a, D: 'a>(
         mut req: Request,
         ctx: RouteContext<D>,
-    ) -> worker::Result<Response> {
-        let dispute: Dispute = req.json().await?;
-
-        let shop = ctx.param("store").expect("Failed to find store param");
+    ) -> Result<Response> {
         let db = ctx.env.d1(DB_BINDING)?;
 
-        db.exec(&format!(
-            "INSERT INTO Disputes VALUES ({}, {}, '{}', '{}', '{}', '{}', '{}', '{}', '{}', {}, '{}');",
-            dispute.id,
-            if let Some(order_id) = &dispute.order_id {
-                order_id.to_string()
-            } else {
-                "NULL".to_string()
-            },
-            dispute.r#type,
-            dispute.amount,
-            dispute.currency,
-            dispute.reason,
-            dispute.status,
-            dispute.initiated_at,
-            dispute.evidence_due_by,
-            if let Some(sent_on) = &dispute.evidence_sent_on {
-                format!("'{sent_on}'")
-            } else {
-                "NULL".to_string()
-            },
-            shop,
-        ))
-        .await?;
+        let (event, dispute) = match webhook::receive::<Dispute>(&mut req, &ctx.env, &db).await? {
+            Ok(received) => received,
+            Err(resp) => return Ok(resp),
+        };
+        let shop = store_param(&ctx)?;
+
+        let version = dispute.version(&event);
+        repo::upsert_disputes(&db, std::slice::from_ref(&dispute), shop, version).await?;
+        refresh_evidence(&db, shop, dispute.id).await?;
+        event.finish(&db, EventStatus::Processed).await?;
 
-        Response::ok("ok")
+        Ok(Response::ok("ok")?)
     }
 
     pub async fn handle_update_webhook<'a, D: 'a>(
         mut req: Request,
         ctx: RouteContext<D>,
-    ) -> worker::Result<Response> {
-        let dispute: Dispute = req.json().await?;
-
+    ) -> Result<Response> {
         let db = ctx.env.d1(DB_BINDING)?;
 
-        db.exec(&format!(
-            "UPDATE Disputes SET order_id = {}, type = '{}', amount = '{}', currency = '{}', reason = '{}', status = '{}', evidence_due_by = '{}', evidence_sent_on = '{}', evidence_sent_on = {} WHERE id = {};",
-            if let Some(order_id) = &dispute.order_id {
-                order_id.to_string()
-            } else {
-                "NULL".to_string()
-            },
-            dispute.r#type,
-            dispute.amount,
-            dispute.currency,
-            dispute.reason,
-            dispute.status,
-            dispute.initiated_at,
-            dispute.evidence_due_by,
-            if let Some(sent_on) = &dispute.evidence_sent_on {
-                format!("'{sent_on}'")
-            } else {
-                "NULL".to_string()
-            },
-            dispute.id,
-        ))
-        .await?;
+        let (event, dispute) = match webhook::receive::<Dispute>(&mut req, &ctx.env, &db).await? {
+            Ok(received) => received,
+            Err(resp) => return Ok(resp),
+        };
+        let shop = store_param(&ctx)?;
+
+        let version = dispute.version(&event);
+        // Upserted as the update can arrive before the dispute was stored, e.g. when its
+        // create webhook is late or the backfill hasn't got to it
+        let status = if repo::upsert_dispute(&db, &dispute, shop, version).await? {
+            refresh_evidence(&db, shop, dispute.id).await?;
+            EventStatus::Processed
+        } else {
+            worker::console_log!(
+                "Ignoring stale disputes/update for dispute {}, a newer state is already stored",
+                dispute.id
+            );
+            EventStatus::Stale
+        };
+        event.finish(&db, status).await?;
+
+        Ok(Response::ok("ok")?)
+    }
+}
 
-        Response::ok("ok")
+/// Looks up the store's token to refresh the evidence from a webhook.
+async fn refresh_evidence(db: &D1Database, shop: &str, dispute_id: ShopifyId) -> Result<()> {
+    match repo::access_token(db, shop).await? {
+        Some(access_token) => {
+            DisputeEvidence::refresh(db, &Token { access_token }, shop, dispute_id).await
+        }
+        // The store was uninstalled, so there's no way to fetch it
+        None => Ok(()),
     }
 }
 
@@ -94,17 +185,13 @@ pub struct Disputes {
 }
 
 impl Disputes {
-    pub async fn fetch(token: &Token, shop: &str) -> worker::Result<Self> {
-        let mut resp = fetch(
-            token,
-            Request::new_with_init(
-                &format!(
-                    "https://{shop}/admin/api/2023-01/shopify_payments/disputes.json?limit=250"
-                ),
-                &RequestInit::default(),
-            )?,
-        )
-        .await?;
+    pub fn first_page_url(shop: &str) -> String {
+        format!("https://{shop}/admin/api/2023-01/shopify_payments/disputes.json?limit=250")
+    }
+
+    /// Fetches one page of disputes and the url of the next one.
+    pub async fn fetch_page(token: &Token, url: &str) -> Result<(Self, Option<String>)> {
+        let (mut resp, next) = paginate::fetch_page(token, url).await?;
 
         // When there are no disputes it returns an empty body with content-type html
         if !resp
@@ -113,63 +200,15 @@ impl Disputes {
             .unwrap_or_default()
             .contains("application/json")
         {
-            return Ok(Disputes {
-                disputes: Vec::default(),
-            });
-        }
-        let mut disputes: Disputes = resp.json().await?;
-
-        let mut link = resp.headers().get("Link")?;
-
-        while let Some(url) = link {
-            let mut resp = fetch(token, Request::new(&url, Method::Get)?).await?;
-
-            let next_disputes: Disputes = resp.json().await?;
-            disputes.disputes.extend(next_disputes.disputes);
-
-            link = resp.headers().get("Link")?;
+            return Ok((Disputes { disputes: vec![] }, None));
         }
 
-        Ok(disputes)
+        Ok((client::json(&mut resp).await?, next))
     }
 
-    pub async fn insert_in_db(&self, db: &D1Database, shop: &str) -> worker::Result<()> {
-        let disputes = self
-            .disputes
-            .iter()
-            .map(|dispute| {
-                format!(
-                    "({}, {}, '{}', '{}', '{}', '{}', '{}', '{}', '{}', {}, '{}')",
-                    dispute.id,
-                    if let Some(order_id) = &dispute.order_id {
-                        order_id.to_string()
-                    } else {
-                        "NULL".to_string()
-                    },
-                    dispute.r#type,
-                    dispute.amount,
-                    dispute.currency,
-                    dispute.reason,
-                    dispute.status,
-                    dispute.initiated_at,
-                    dispute.evidence_due_by,
-                    if let Some(sent_on) = &dispute.evidence_sent_on {
-                        format!("'{sent_on}'")
-                    } else {
-                        "NULL".to_string()
-                    },
-                    shop,
-                )
-            })
-            .collect::<Vec<_>>()
-            .join(",");
-
-        if !disputes.is_empty() {
-            db.exec(&format!("INSERT INTO Disputes VALUES {};", disputes))
-                .await?;
-        }
-
-        Ok(())
+    pub async fn insert_in_db(&self, db: &D1Database, shop: &str) -> Result<()> {
+        // What was just fetched is the current state, so it's newer than anything stored
+        let version = time::OffsetDateTime::now_utc().unix_timestamp_nanos() / 1_000_000;
+        Ok(repo::upsert_disputes(db, &self.disputes, shop, Some(version as i64)).await?)
     }
 }
-
@@ -0,0 +1,96 @@
+use std::fmt;
+
+use worker::Response;
+
+use crate::{client::FetchError, repo::DbError};
+
+pub type Result<T, E = Error> = std::result::Result<T, E>;
+
+/// Everything a handler can fail with. Each variant maps to the status the request is
+/// answered with, see [`Error::into_response`].
+#[derive(Debug)]
+pub enum Error {
+    /// The request is malformed or missing something. 400
+    BadRequest(String),
+    /// A hmac, OAuth state or bearer token didn't check out. 401
+    Unauthorized(String),
+    /// Shopify kept throttling the calls made for the request. 429
+    RateLimited(String),
+    /// Shopify failed or answered with something unexpected. 502
+    Upstream(String),
+    /// A statement against D1 failed. 500
+    Database(worker::Error),
+    /// Anything else the worker runtime failed at. 500
+    Internal(worker::Error),
+}
+
+impl Error {
+    pub fn status(&self) -> u16 {
+        match self {
+            Error::BadRequest(_) => 400,
+            Error::Unauthorized(_) => 401,
+            Error::RateLimited(_) => 429,
+            Error::Upstream(_) => 502,
+            Error::Database(_) | Error::Internal(_) => 500,
+        }
+    }
+
+    /// The single place errors turn into responses. Server side failures are logged and
+    /// answered without their details.
+    pub fn into_response(self) -> worker::Result<Response> {
+        let status = self.status();
+
+        let message = match self {
+            Error::BadRequest(message)
+            | Error::Unauthorized(message)
+            | Error::RateLimited(message) => message,
+            e => {
+                worker::console_error!("{e}");
+
+                match e {
+                    Error::Upstream(_) => "Shopify request failed".to_string(),
+                    _ => "Internal error".to_string(),
+                }
+            }
+        };
+
+        Response::error(message, status)
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::BadRequest(message) => write!(f, "Bad request: {message}"),
+            Error::Unauthorized(message) => write!(f, "Unauthorized: {message}"),
+            Error::RateLimited(message) => write!(f, "Rate limited: {message}"),
+            Error::Upstream(message) => write!(f, "Shopify error: {message}"),
+            Error::Database(e) => write!(f, "Database error: {e}"),
+            Error::Internal(e) => write!(f, "Internal error: {e}"),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl From<worker::Error> for Error {
+    fn from(e: worker::Error) -> Self {
+        Error::Internal(e)
+    }
+}
+
+impl From<DbError> for Error {
+    fn from(DbError(e): DbError) -> Self {
+        Error::Database(e)
+    }
+}
+
+impl From<FetchError> for Error {
+    fn from(e: FetchError) -> Self {
+        match e {
+            FetchError::RetriesExhausted { status: 429, .. } => Error::RateLimited(e.to_string()),
+            FetchError::RetriesExhausted { .. } => Error::Upstream(e.to_string()),
+            FetchError::Worker(e) => Error::Upstream(e.to_string()),
+        }
+    }
+}
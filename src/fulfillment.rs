@@ -0,0 +1,49 @@
+use worker::{Request, Response, RouteContext};
+
+use crate::{
+    repo,
+    shopify_id::ShopifyId,
+    store_param,
+    webhook::{self, EventStatus},
+    Result, DB_BINDING,
+};
+
+#[derive(Debug, serde::Deserialize)]
+pub struct Fulfillment {
+    pub(crate) id: ShopifyId,
+    pub(crate) order_id: ShopifyId,
+    /// `pending`, `open`, `success`, `cancelled`, `error` or `failure`.
+    pub(crate) status: Option<String>,
+    pub(crate) tracking_company: Option<String>,
+    #[serde(default)]
+    pub(crate) tracking_numbers: Vec<String>,
+    #[serde(default)]
+    pub(crate) tracking_urls: Vec<String>,
+    /// The carrier's latest status for the shipment, e.g. `in_transit` or `delivered`.
+    pub(crate) shipment_status: Option<String>,
+    pub(crate) created_at: Option<String>,
+    pub(crate) updated_at: Option<String>,
+}
+
+impl Fulfillment {
+    /// Handles `fulfillments/create` and `fulfillments/update`, which both deliver the
+    /// full fulfillment.
+    pub async fn handle_webhook<'a, D: 'a>(
+        mut req: Request,
+        ctx: RouteContext<D>,
+    ) -> Result<Response> {
+        let db = ctx.env.d1(DB_BINDING)?;
+
+        let (event, fulfillment) =
+            match webhook::receive::<Fulfillment>(&mut req, &ctx.env, &db).await? {
+                Ok(received) => received,
+                Err(resp) => return Ok(resp),
+            };
+        let shop = store_param(&ctx)?;
+
+        repo::upsert_fulfillments(&db, std::slice::from_ref(&fulfillment), shop).await?;
+        event.finish(&db, EventStatus::Processed).await?;
+
+        Ok(Response::ok("ok")?)
+    }
+}
@@ -1,64 +1,86 @@
-use worker::{D1Database, Method, Request, RequestInit, Response, RouteContext};
-
-use crate::{fetch, Customer, Token, DB_BINDING};
+use worker::{D1Database, Request, Response, RouteContext};
+
+use crate::{
+    client,
+    customer::Customer,
+    fulfillment::Fulfillment,
+    paginate, repo,
+    shopify_id::ShopifyId,
+    store_param,
+    webhook::{self, EventStatus},
+    Result, Token, DB_BINDING,
+};
 
 #[derive(Debug, serde::Deserialize)]
-struct LineItem {
-    title: String,
+pub struct LineItem {
+    pub(crate) id: ShopifyId,
+    pub(crate) title: String,
+    /// `None` for custom line items that aren't backed by a product.
+    pub(crate) product_id: Option<ShopifyId>,
+    pub(crate) variant_id: Option<ShopifyId>,
+    pub(crate) sku: Option<String>,
+    pub(crate) vendor: Option<String>,
+    pub(crate) quantity: u64,
+    pub(crate) price: String,
+    pub(crate) total_discount: String,
+    pub(crate) taxable: bool,
 }
 
 #[derive(Debug, serde::Deserialize)]
 pub struct Order {
-    id: f64,
-    customer: Customer,
-    line_items: Vec<LineItem>,
+    pub(crate) id: ShopifyId,
+    /// The order number as shown to the merchant, e.g. `#1001`.
+    pub(crate) name: String,
+    /// `None` for orders imported through a bulk operation, which don't carry it.
+    pub(crate) order_number: Option<u64>,
+    /// `None` for orders placed without a customer, e.g. some POS sales.
+    pub(crate) customer: Option<Customer>,
+    pub(crate) line_items: Vec<LineItem>,
+    pub(crate) created_at: String,
+    pub(crate) processed_at: Option<String>,
+    pub(crate) total_price: String,
+    pub(crate) subtotal_price: Option<String>,
+    pub(crate) total_tax: Option<String>,
+    pub(crate) total_discounts: Option<String>,
+    pub(crate) currency: String,
+    pub(crate) presentment_currency: Option<String>,
+    pub(crate) financial_status: Option<String>,
+    pub(crate) fulfillment_status: Option<String>,
+    pub(crate) cancelled_at: Option<String>,
+    pub(crate) cancel_reason: Option<String>,
+    /// Comma separated, as Shopify sends them.
+    #[serde(default)]
+    pub(crate) tags: String,
+    pub(crate) source_name: Option<String>,
+    /// The token of the checkout the order was placed through.
+    pub(crate) checkout_token: Option<String>,
+    #[serde(default)]
+    pub(crate) fulfillments: Vec<Fulfillment>,
 }
 
 impl Order {
-    pub async fn insert_in_db(&self, db: &D1Database, shop: &str) -> worker::Result<()> {
-        db.exec(&format!(
-            "INSERT INTO Orders VALUES ({}, {}, {}, {}, '{}');",
-            self.id,
-            if let Some(name) = &self.customer.first_name {
-                format!("'{name}'")
-            } else {
-                "NULL".to_string()
-            },
-            if let Some(name) = &self.customer.last_name {
-                format!("'{name}'")
-            } else {
-                "NULL".to_string()
-            },
-            if let Some(email) = &self.customer.email {
-                format!("'{email}'")
-            } else {
-                "NULL".to_string()
-            },
-            shop,
-        ))
-        .await?;
-
-        for item in &self.line_items {
-            db.prepare(format!("INSERT INTO LineItems VALUES (?, {});", self.id))
-                .bind(&[item.title.as_str().into()])?
-                .all()
-                .await?;
-        }
-        Ok(())
+    pub async fn insert_in_db(&self, db: &D1Database, shop: &str) -> Result<()> {
+        Ok(repo::upsert_orders(db, std::slice::from_ref(self), shop).await?)
     }
 
+    /// Handles `orders/paid`, `orders/updated` and `orders/cancelled`, which all
+    /// deliver the full order.
     pub async fn handle_webhook<'a, D: 'a>(
         mut req: Request,
         ctx: RouteContext<D>,
-    ) -> worker::Result<Response> {
-        let order: Order = req.json().await?;
-        let shop = ctx.param("store").expect("Failed to find store param");
-
+    ) -> Result<Response> {
         let db = ctx.env.d1(DB_BINDING)?;
 
+        let (event, order) = match webhook::receive::<Order>(&mut req, &ctx.env, &db).await? {
+            Ok(received) => received,
+            Err(resp) => return Ok(resp),
+        };
+        let shop = store_param(&ctx)?;
+
         order.insert_in_db(&db, shop).await?;
+        event.finish(&db, EventStatus::Processed).await?;
 
-        Response::ok("ok")
+        Ok(Response::ok("ok")?)
     }
 }
 
@@ -68,83 +90,18 @@ pub struct Orders {
 }
 
 impl Orders {
-    pub async fn fetch(token: &Token, shop: &str) -> worker::Result<Self> {
-        let mut resp = fetch(
-            token,
-            Request::new_with_init(
-                &format!("https://{shop}/admin/api/2023-01/orders.json?financial_status=paid&fields=id,customer,line_items&limit=250"),
-                &RequestInit::default(),
-            )?,
-        )
-        .await?;
-
-        let mut orders: Orders = resp.json().await?;
-
-        let mut link = resp.headers().get("Link")?;
-
-        while let Some(url) = link {
-            let mut resp = fetch(token, Request::new(&url, Method::Get)?).await?;
-
-            let next_orders: Orders = resp.json().await?;
-            orders.orders.extend(next_orders.orders);
-
-            link = resp.headers().get("Link")?;
-        }
-
-        Ok(orders)
+    pub fn first_page_url(shop: &str) -> String {
+        format!("https://{shop}/admin/api/2023-01/orders.json?financial_status=paid&fields=id,name,order_number,customer,line_items,created_at,processed_at,total_price,subtotal_price,total_tax,total_discounts,currency,presentment_currency,financial_status,fulfillment_status,cancelled_at,cancel_reason,tags,source_name,checkout_token,fulfillments&limit=250")
     }
 
-    pub async fn insert_in_db(&self, db: &D1Database, shop: &str) -> worker::Result<()> {
-        let orders = self
-            .orders
-            .iter()
-            .map(|order| {
-                format!(
-                    "({}, {}, {}, {}, '{}')",
-                    order.id,
-                    if let Some(name) = &order.customer.first_name {
-                        format!("'{name}'")
-                    } else {
-                        "NULL".to_string()
-                    },
-                    if let Some(name) = &order.customer.last_name {
-                        format!("'{name}'")
-                    } else {
-                        "NULL".to_string()
-                    },
-                    if let Some(email) = &order.customer.email {
-                        format!("'{email}'")
-                    } else {
-                        "NULL".to_string()
-                    },
-                    shop,
-                )
-            })
-            .collect::<Vec<_>>()
-            .join(",");
+    /// Fetches one page of orders and the url of the next one.
+    pub async fn fetch_page(token: &Token, url: &str) -> Result<(Self, Option<String>)> {
+        let (mut resp, next) = paginate::fetch_page(token, url).await?;
 
-        if !orders.is_empty() {
-            db.exec(&format!("INSERT INTO Orders VALUES {};", orders))
-                .await?;
-        }
-
-        let line_items = self
-            .orders
-            .iter()
-            .flat_map(|order| {
-                order
-                    .line_items
-                    .iter()
-                    .map(|item| format!("('{}', {})", item.title, order.id))
-            })
-            .collect::<Vec<_>>()
-            .join(",");
-
-        if !line_items.is_empty() {
-            db.exec(&format!("INSERT INTO LineItems VALUES {};", line_items))
-                .await?;
-        }
+        Ok((client::json(&mut resp).await?, next))
+    }
 
-        Ok(())
+    pub async fn insert_in_db(&self, db: &D1Database, shop: &str) -> Result<()> {
+        Ok(repo::upsert_orders(db, &self.orders, shop).await?)
     }
 }
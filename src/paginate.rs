@@ -0,0 +1,133 @@
+//! Shopify's REST endpoints are paged with a cursor that is handed out through the
+//! `Link` response header, e.g.
+//! `<https://shop/admin/api/2023-01/orders.json?page_info=abc>; rel="previous", <https://shop/admin/api/2023-01/orders.json?page_info=def>; rel="next"`.
+
+use worker::{Method, Request, Response};
+
+use crate::{fetch, Result, Token};
+
+/// Walks a paged endpoint by following only the `rel="next"` links.
+pub struct Pages<'a> {
+    token: &'a Token,
+    next: Option<String>,
+}
+
+impl<'a> Pages<'a> {
+    pub fn new(token: &'a Token, url: String) -> Self {
+        Pages {
+            token,
+            next: Some(url),
+        }
+    }
+
+    /// Fetches the next page, or returns `None` once the last page has been fetched.
+    pub async fn next(&mut self) -> Result<Option<Response>> {
+        let url = match self.next.take() {
+            Some(url) => url,
+            None => return Ok(None),
+        };
+
+        let (resp, next) = fetch_page(self.token, &url).await?;
+        self.next = next;
+
+        Ok(Some(resp))
+    }
+}
+
+/// Fetches a single page along with the url of the page after it, which is the cursor a
+/// backfill resumes from.
+pub async fn fetch_page(token: &Token, url: &str) -> Result<(Response, Option<String>)> {
+    let resp = fetch(token, Request::new(url, Method::Get)?).await?;
+
+    let next = resp.headers().get("Link")?.as_deref().and_then(next_link);
+
+    Ok((resp, next))
+}
+
+/// Picks the target of the `rel="next"` link out of an RFC 8288 `Link` header.
+///
+/// The header can't simply be split on `,` as the urls Shopify hands out contain
+/// commas themselves (`fields=id,customer`).
+fn next_link(header: &str) -> Option<String> {
+    let mut rest = header;
+
+    while let Some(start) = rest.find('<') {
+        let end = start + rest[start..].find('>')?;
+        let url = &rest[start + 1..end];
+        rest = &rest[end + 1..];
+
+        // The parameters of this link run until the next one starts
+        let params = match rest.find('<') {
+            Some(next) => &rest[..next],
+            None => rest,
+        };
+
+        let is_next = params.split(';').any(|param| match param.split_once('=') {
+            Some((name, value)) => {
+                name.trim().eq_ignore_ascii_case("rel")
+                    && value
+                        .trim_matches(|c: char| c == '"' || c == ',' || c.is_whitespace())
+                        .split_ascii_whitespace()
+                        .any(|rel| rel.eq_ignore_ascii_case("next"))
+            }
+            None => false,
+        });
+
+        if is_next {
+            return Some(url.to_string());
+        }
+    }
+
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn picks_the_next_link() {
+        let header = r#"<https://shop/admin/api/2023-01/orders.json?page_info=abc>; rel="previous", <https://shop/admin/api/2023-01/orders.json?page_info=def>; rel="next""#;
+
+        assert_eq!(
+            next_link(header).as_deref(),
+            Some("https://shop/admin/api/2023-01/orders.json?page_info=def")
+        );
+    }
+
+    #[test]
+    fn has_no_next_link_on_the_last_page() {
+        let header =
+            r#"<https://shop/admin/api/2023-01/orders.json?page_info=abc>; rel="previous""#;
+
+        assert_eq!(next_link(header), None);
+    }
+
+    #[test]
+    fn keeps_commas_in_urls() {
+        let header = r#"<https://shop/admin/api/2023-01/orders.json?fields=id,customer&page_info=def>; rel="next""#;
+
+        assert_eq!(
+            next_link(header).as_deref(),
+            Some("https://shop/admin/api/2023-01/orders.json?fields=id,customer&page_info=def")
+        );
+    }
+
+    #[test]
+    fn reads_unquoted_and_multiple_rels() {
+        assert_eq!(
+            next_link("<https://shop/a>; rel=next").as_deref(),
+            Some("https://shop/a")
+        );
+        assert_eq!(
+            next_link(r#"<https://shop/a>; rel="prefetch NEXT""#).as_deref(),
+            Some("https://shop/a")
+        );
+    }
+
+    #[test]
+    fn ignores_a_malformed_header() {
+        assert_eq!(next_link(""), None);
+        assert_eq!(next_link(r#"<https://shop/a; rel="next""#), None);
+    }
+}
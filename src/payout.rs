@@ -0,0 +1,132 @@
+//! Shopify Payments payouts and the balance transactions that make them up. A
+//! balance transaction points at what moved the money through `source_type` and
+//! `source_id`, e.g. a `dispute` debit or a `refund`, and at the order it belongs to.
+
+use worker::Response;
+
+use crate::{
+    client,
+    paginate::{self, Pages},
+    shopify_id::ShopifyId,
+    Result, Token,
+};
+
+#[derive(Debug, serde::Deserialize)]
+pub struct Payout {
+    pub(crate) id: ShopifyId,
+    /// `scheduled`, `in_transit`, `paid`, `failed` or `canceled`.
+    pub(crate) status: String,
+    pub(crate) date: String,
+    pub(crate) currency: String,
+    pub(crate) amount: String,
+}
+
+#[derive(Debug, serde::Deserialize)]
+pub struct BalanceTransaction {
+    pub(crate) id: ShopifyId,
+    /// `None` until the transaction is assigned to a payout.
+    pub(crate) payout_id: Option<ShopifyId>,
+    pub(crate) payout_status: Option<String>,
+    /// `charge`, `refund`, `dispute`, `adjustment`, ...
+    pub(crate) r#type: String,
+    #[serde(default)]
+    pub(crate) test: bool,
+    pub(crate) currency: String,
+    pub(crate) amount: String,
+    pub(crate) fee: String,
+    pub(crate) net: String,
+    pub(crate) source_id: Option<ShopifyId>,
+    pub(crate) source_type: Option<String>,
+    pub(crate) source_order_id: Option<ShopifyId>,
+    pub(crate) source_order_transaction_id: Option<ShopifyId>,
+    pub(crate) processed_at: Option<String>,
+}
+
+/// Fetches the payouts dated on or after `date_min` (`YYYY-MM-DD`).
+pub async fn fetch_payouts(token: &Token, shop: &str, date_min: &str) -> Result<Vec<Payout>> {
+    #[derive(serde::Deserialize)]
+    struct Page {
+        payouts: Vec<Payout>,
+    }
+
+    let mut pages = Pages::new(
+        token,
+        format!("https://{shop}/admin/api/2023-01/shopify_payments/payouts.json?date_min={date_min}&limit=250"),
+    );
+    let mut payouts = Vec::new();
+    while let Some(mut resp) = pages.next().await? {
+        if !is_json(&resp)? {
+            break;
+        }
+
+        let page: Page = client::json(&mut resp).await?;
+        payouts.extend(page.payouts);
+    }
+
+    Ok(payouts)
+}
+
+/// The first page of a store's payouts, `limit` at a time. Each payout costs another call
+/// for its balance transactions, so the backfill keeps its pages short.
+pub fn first_page_url(shop: &str, limit: u32) -> String {
+    format!("https://{shop}/admin/api/2023-01/shopify_payments/payouts.json?limit={limit}")
+}
+
+/// Fetches one page of payouts and the url of the next one, or `None` if the store hasn't
+/// granted `read_shopify_payments_payouts`, which Shopify answers with a 403.
+pub async fn fetch_page(token: &Token, url: &str) -> Result<Option<(Vec<Payout>, Option<String>)>> {
+    #[derive(serde::Deserialize)]
+    struct Page {
+        payouts: Vec<Payout>,
+    }
+
+    let (mut resp, next) = paginate::fetch_page(token, url).await?;
+    if resp.status_code() == 403 {
+        return Ok(None);
+    }
+    if !is_json(&resp)? {
+        return Ok(Some((Vec::new(), None)));
+    }
+
+    let page: Page = client::json(&mut resp).await?;
+
+    Ok(Some((page.payouts, next)))
+}
+
+/// Fetches the balance transactions paid out in the payout.
+pub async fn fetch_balance_transactions(
+    token: &Token,
+    shop: &str,
+    payout_id: ShopifyId,
+) -> Result<Vec<BalanceTransaction>> {
+    #[derive(serde::Deserialize)]
+    struct Page {
+        transactions: Vec<BalanceTransaction>,
+    }
+
+    let mut pages = Pages::new(
+        token,
+        format!("https://{shop}/admin/api/2023-01/shopify_payments/balance/transactions.json?payout_id={payout_id}&limit=250"),
+    );
+    let mut transactions = Vec::new();
+    while let Some(mut resp) = pages.next().await? {
+        if !is_json(&resp)? {
+            break;
+        }
+
+        let page: Page = client::json(&mut resp).await?;
+        transactions.extend(page.transactions);
+    }
+
+    Ok(transactions)
+}
+
+/// Like the disputes endpoint, these return an empty html body for stores without
+/// Shopify Payments.
+fn is_json(resp: &Response) -> Result<bool> {
+    Ok(resp
+        .headers()
+        .get("content-type")?
+        .unwrap_or_default()
+        .contains("application/json"))
+}
@@ -0,0 +1,107 @@
+use worker::{D1Database, Request, Response, RouteContext};
+
+use crate::{
+    client, paginate, repo,
+    shopify_id::ShopifyId,
+    store_param,
+    webhook::{self, EventStatus},
+    Result, Token, DB_BINDING,
+};
+
+#[derive(Debug, serde::Deserialize)]
+pub struct Variant {
+    pub(crate) id: ShopifyId,
+    pub(crate) title: String,
+    pub(crate) sku: Option<String>,
+    pub(crate) barcode: Option<String>,
+    pub(crate) price: String,
+    pub(crate) compare_at_price: Option<String>,
+    pub(crate) inventory_quantity: Option<i64>,
+    pub(crate) created_at: Option<String>,
+    pub(crate) updated_at: Option<String>,
+}
+
+#[derive(Debug, serde::Deserialize)]
+pub struct Product {
+    pub(crate) id: ShopifyId,
+    pub(crate) title: String,
+    pub(crate) vendor: Option<String>,
+    pub(crate) product_type: Option<String>,
+    pub(crate) handle: Option<String>,
+    /// `active`, `archived` or `draft`.
+    pub(crate) status: Option<String>,
+    /// Comma separated, as Shopify sends them.
+    #[serde(default)]
+    pub(crate) tags: String,
+    pub(crate) created_at: Option<String>,
+    pub(crate) updated_at: Option<String>,
+    #[serde(default)]
+    pub(crate) variants: Vec<Variant>,
+}
+
+impl Product {
+    /// Handles `products/create` and `products/update`, which both deliver the full
+    /// product including its variants.
+    pub async fn handle_webhook<'a, D: 'a>(
+        mut req: Request,
+        ctx: RouteContext<D>,
+    ) -> Result<Response> {
+        let db = ctx.env.d1(DB_BINDING)?;
+
+        let (event, product) = match webhook::receive::<Product>(&mut req, &ctx.env, &db).await? {
+            Ok(received) => received,
+            Err(resp) => return Ok(resp),
+        };
+        let shop = store_param(&ctx)?;
+
+        repo::upsert_products(&db, std::slice::from_ref(&product), shop).await?;
+        event.finish(&db, EventStatus::Processed).await?;
+
+        Ok(Response::ok("ok")?)
+    }
+
+    /// Handles `products/delete`, which only delivers the id of the product.
+    pub async fn handle_delete_webhook<'a, D: 'a>(
+        mut req: Request,
+        ctx: RouteContext<D>,
+    ) -> Result<Response> {
+        #[derive(serde::Deserialize)]
+        struct ReqBody {
+            id: ShopifyId,
+        }
+
+        let db = ctx.env.d1(DB_BINDING)?;
+
+        let (event, body) = match webhook::receive::<ReqBody>(&mut req, &ctx.env, &db).await? {
+            Ok(received) => received,
+            Err(resp) => return Ok(resp),
+        };
+
+        repo::delete_product(&db, body.id).await?;
+        event.finish(&db, EventStatus::Processed).await?;
+
+        Ok(Response::ok("ok")?)
+    }
+}
+
+#[derive(serde::Deserialize)]
+pub struct Products {
+    products: Vec<Product>,
+}
+
+impl Products {
+    pub fn first_page_url(shop: &str) -> String {
+        format!("https://{shop}/admin/api/2023-01/products.json?fields=id,title,vendor,product_type,handle,status,tags,created_at,updated_at,variants&limit=250")
+    }
+
+    /// Fetches one page of products and the url of the next one.
+    pub async fn fetch_page(token: &Token, url: &str) -> Result<(Self, Option<String>)> {
+        let (mut resp, next) = paginate::fetch_page(token, url).await?;
+
+        Ok((client::json(&mut resp).await?, next))
+    }
+
+    pub async fn insert_in_db(&self, db: &D1Database, shop: &str) -> Result<()> {
+        Ok(repo::upsert_products(db, &self.products, shop).await?)
+    }
+}
@@ -0,0 +1,82 @@
+use worker::{Request, Response, RouteContext};
+
+use crate::{
+    repo,
+    shopify_id::ShopifyId,
+    store_param,
+    transaction::Transaction,
+    webhook::{self, EventStatus},
+    Result, DB_BINDING,
+};
+
+#[derive(Debug, serde::Deserialize)]
+pub struct Refund {
+    pub(crate) id: ShopifyId,
+    pub(crate) order_id: ShopifyId,
+    pub(crate) note: Option<String>,
+    pub(crate) created_at: String,
+    pub(crate) processed_at: Option<String>,
+    #[serde(default)]
+    pub(crate) refund_line_items: Vec<RefundLineItem>,
+    /// The money given back, usually a single `refund` transaction.
+    #[serde(default)]
+    pub(crate) transactions: Vec<Transaction>,
+}
+
+#[derive(Debug, serde::Deserialize)]
+pub struct RefundLineItem {
+    pub(crate) id: ShopifyId,
+    pub(crate) line_item_id: ShopifyId,
+    pub(crate) quantity: u64,
+    /// `no_restock`, `cancel`, `return` or `legacy_restock`.
+    pub(crate) restock_type: Option<String>,
+    subtotal_set: Option<MoneySet>,
+    total_tax_set: Option<MoneySet>,
+}
+
+impl RefundLineItem {
+    /// In the shop's currency. The plain `subtotal` is sent as a float.
+    pub(crate) fn subtotal(&self) -> Option<&str> {
+        self.subtotal_set.as_ref().map(MoneySet::amount)
+    }
+
+    pub(crate) fn total_tax(&self) -> Option<&str> {
+        self.total_tax_set.as_ref().map(MoneySet::amount)
+    }
+}
+
+#[derive(Debug, serde::Deserialize)]
+struct Money {
+    amount: String,
+}
+
+#[derive(Debug, serde::Deserialize)]
+struct MoneySet {
+    shop_money: Money,
+}
+
+impl MoneySet {
+    fn amount(&self) -> &str {
+        &self.shop_money.amount
+    }
+}
+
+impl Refund {
+    pub async fn handle_create_webhook<'a, D: 'a>(
+        mut req: Request,
+        ctx: RouteContext<D>,
+    ) -> Result<Response> {
+        let db = ctx.env.d1(DB_BINDING)?;
+
+        let (event, refund) = match webhook::receive::<Refund>(&mut req, &ctx.env, &db).await? {
+            Ok(received) => received,
+            Err(resp) => return Ok(resp),
+        };
+        let shop = store_param(&ctx)?;
+
+        repo::upsert_refund(&db, &refund, shop).await?;
+        event.finish(&db, EventStatus::Processed).await?;
+
+        Ok(Response::ok("ok")?)
+    }
+}
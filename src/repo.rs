@@ -0,0 +1,1224 @@
+//! Every statement the worker runs against D1 lives in this module. Values are
+//! always passed through `.bind()` so nothing coming from Shopify or a request
+//! ever ends up in the SQL text.
+
+use worker::{wasm_bindgen::JsValue, D1Database, D1PreparedStatement};
+
+use crate::{
+    backfill::{JobStatus, Resource},
+    checkout::Checkout,
+    customer::Customer,
+    dispute::{Dispute, DisputeEvidence},
+    fulfillment::Fulfillment,
+    order::Order,
+    payout::{BalanceTransaction, Payout},
+    product::Product,
+    refund::Refund,
+    shopify_id::ShopifyId,
+    transaction::Transaction,
+    webhook::EventStatus,
+};
+
+/// A failed statement. Every function here returns it so `?` tags worker errors as
+/// database errors on their way into [`crate::Error`].
+#[derive(Debug)]
+pub struct DbError(pub worker::Error);
+
+impl From<worker::Error> for DbError {
+    fn from(e: worker::Error) -> Self {
+        DbError(e)
+    }
+}
+
+type Result<T> = std::result::Result<T, DbError>;
+
+fn nullable<T: Into<JsValue>>(value: Option<T>) -> JsValue {
+    value.map(Into::into).unwrap_or(JsValue::NULL)
+}
+
+/// Binds ids as one JSON array, read back with `IN (SELECT value FROM json_each(?))`, as
+/// D1 caps a statement at 100 bound values.
+fn id_list(ids: &[ShopifyId]) -> worker::Result<JsValue> {
+    Ok(serde_json::to_string(ids)?.into())
+}
+
+#[derive(serde::Deserialize)]
+pub struct Store {
+    pub name: String,
+    pub access_token: String,
+    pub last_abandoned_checkout_sync: Option<i64>,
+    pub last_payout_sync: Option<i64>,
+}
+
+/// A dispute that still needs a response from the merchant.
+#[derive(serde::Deserialize)]
+pub struct DisputeAwaitingEvidence {
+    pub id: ShopifyId,
+    pub store_name: String,
+    pub order_id: Option<ShopifyId>,
+    pub amount: String,
+    pub currency: String,
+    pub reason: String,
+    pub evidence_due_by: String,
+}
+
+#[derive(serde::Serialize, serde::Deserialize)]
+pub struct DbCustomer {
+    id: ShopifyId,
+    store_name: String,
+    email: Option<String>,
+    first_name: Option<String>,
+    last_name: Option<String>,
+    phone: Option<String>,
+    /// SQLite has no booleans so this comes back as `0` or `1`.
+    accepts_marketing: Option<u8>,
+    total_spent: Option<String>,
+    orders_count: Option<u64>,
+    created_at: Option<String>,
+}
+
+#[derive(serde::Serialize, serde::Deserialize)]
+pub struct DbDisputeEvidence {
+    dispute_id: ShopifyId,
+    id: ShopifyId,
+    access_activity_log: Option<String>,
+    /// JSON, as Shopify sends it.
+    billing_address: Option<String>,
+    /// JSON, as Shopify sends it.
+    shipping_address: Option<String>,
+    cancellation_policy_disclosure: Option<String>,
+    cancellation_rebuttal: Option<String>,
+    customer_email_address: Option<String>,
+    customer_first_name: Option<String>,
+    customer_last_name: Option<String>,
+    product_description: Option<String>,
+    refund_policy_disclosure: Option<String>,
+    refund_refusal_explanation: Option<String>,
+    uncategorized_text: Option<String>,
+    fulfillments: String,
+    submitted_by_merchant_on: Option<String>,
+    created_at: Option<String>,
+    updated_at: Option<String>,
+    fetched_at: i64,
+}
+
+#[derive(serde::Serialize, serde::Deserialize)]
+pub struct DbOrder {
+    id: ShopifyId,
+    customer_id: Option<ShopifyId>,
+    store_name: String,
+    cancelled_at: Option<String>,
+    cancel_reason: Option<String>,
+    name: Option<String>,
+    order_number: Option<u64>,
+    created_at: Option<String>,
+    processed_at: Option<String>,
+    total_price: Option<String>,
+    subtotal_price: Option<String>,
+    total_tax: Option<String>,
+    total_discounts: Option<String>,
+    currency: Option<String>,
+    presentment_currency: Option<String>,
+    financial_status: Option<String>,
+    fulfillment_status: Option<String>,
+    tags: Option<String>,
+    source_name: Option<String>,
+    checkout_token: Option<String>,
+    first_name: Option<String>,
+    last_name: Option<String>,
+    email: Option<String>,
+}
+
+#[derive(serde::Serialize, serde::Deserialize)]
+pub struct DbAbandonedCheckout {
+    id: ShopifyId,
+    checkout_url: String,
+    customer_id: Option<ShopifyId>,
+    store_name: String,
+    token: Option<String>,
+    created_at: Option<String>,
+    updated_at: Option<String>,
+    completed_at: Option<String>,
+    total_price: Option<String>,
+    currency: Option<String>,
+    first_name: Option<String>,
+    last_name: Option<String>,
+    email: Option<String>,
+}
+
+/// Reinstalling a store swaps out its access token and reactivates it.
+pub async fn upsert_store(db: &D1Database, shop: &str, access_token: &str) -> Result<()> {
+    db.prepare("INSERT INTO Stores (name, access_token) VALUES (?, ?) ON CONFLICT (name) DO UPDATE SET access_token = excluded.access_token, uninstalled_at = NULL;")
+        .bind(&[shop.into(), access_token.into()])?
+        .run()
+        .await?;
+
+    Ok(())
+}
+
+pub async fn backfill_method(db: &D1Database, shop: &str) -> Result<Option<String>> {
+    Ok(db
+        .prepare("SELECT backfill_method FROM Stores WHERE name = ?;")
+        .bind(&[shop.into()])?
+        .first::<String>(Some("backfill_method"))
+        .await?)
+}
+
+pub async fn set_backfill_method(db: &D1Database, shop: &str, method: &str) -> Result<()> {
+    db.prepare("UPDATE Stores SET backfill_method = ? WHERE name = ?;")
+        .bind(&[method.into(), shop.into()])?
+        .run()
+        .await?;
+
+    Ok(())
+}
+
+/// Stores that still have the app installed.
+pub async fn active_stores(db: &D1Database) -> Result<Vec<Store>> {
+    Ok(db.prepare("SELECT name, access_token, last_abandoned_checkout_sync, last_payout_sync FROM Stores WHERE uninstalled_at IS NULL;")
+        .all()
+        .await?
+        .results::<Store>()?)
+}
+
+/// The access token of the store, unless it has been uninstalled.
+pub async fn access_token(db: &D1Database, shop: &str) -> Result<Option<String>> {
+    Ok(db
+        .prepare("SELECT access_token FROM Stores WHERE name = ? AND uninstalled_at IS NULL;")
+        .bind(&[shop.into()])?
+        .first::<String>(Some("access_token"))
+        .await?)
+}
+
+pub async fn update_last_abandoned_checkout_sync(
+    db: &D1Database,
+    shop: &str,
+    timestamp: i64,
+) -> Result<()> {
+    db.prepare("UPDATE Stores SET last_abandoned_checkout_sync = ? WHERE name = ?;")
+        .bind(&[(timestamp as f64).into(), shop.into()])?
+        .run()
+        .await?;
+
+    Ok(())
+}
+
+/// Hands the store to the payout sync from `timestamp` on, unless it already syncs.
+pub async fn start_payout_sync(db: &D1Database, shop: &str, timestamp: i64) -> Result<()> {
+    db.prepare(
+        "UPDATE Stores SET last_payout_sync = COALESCE(last_payout_sync, ?) WHERE name = ?;",
+    )
+    .bind(&[(timestamp as f64).into(), shop.into()])?
+    .run()
+    .await?;
+
+    Ok(())
+}
+
+pub async fn update_last_payout_sync(db: &D1Database, shop: &str, timestamp: i64) -> Result<()> {
+    db.prepare("UPDATE Stores SET last_payout_sync = ? WHERE name = ?;")
+        .bind(&[(timestamp as f64).into(), shop.into()])?
+        .run()
+        .await?;
+
+    Ok(())
+}
+
+pub async fn mark_store_uninstalled(db: &D1Database, shop: &str, timestamp: i64) -> Result<()> {
+    db.prepare("UPDATE Stores SET uninstalled_at = ? WHERE name = ?;")
+        .bind(&[(timestamp as f64).into(), shop.into()])?
+        .run()
+        .await?;
+
+    Ok(())
+}
+
+pub async fn delete_store(db: &D1Database, shop: &str) -> Result<()> {
+    db.prepare("DELETE FROM Stores WHERE name = ?;")
+        .bind(&[shop.into()])?
+        .run()
+        .await?;
+
+    Ok(())
+}
+
+fn upsert_customer_statement(
+    db: &D1Database,
+    customer: &Customer,
+    shop: &str,
+) -> worker::Result<D1PreparedStatement> {
+    db.prepare("INSERT INTO Customers (id, store_name, email, first_name, last_name, phone, accepts_marketing, total_spent, orders_count, created_at) VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?) ON CONFLICT (id) DO UPDATE SET store_name = excluded.store_name, email = excluded.email, first_name = excluded.first_name, last_name = excluded.last_name, phone = excluded.phone, accepts_marketing = excluded.accepts_marketing, total_spent = excluded.total_spent, orders_count = excluded.orders_count, created_at = excluded.created_at;")
+        .bind(&[
+            customer.id.into(),
+            shop.into(),
+            nullable(customer.email.as_deref()),
+            nullable(customer.first_name.as_deref()),
+            nullable(customer.last_name.as_deref()),
+            nullable(customer.phone.as_deref()),
+            nullable(customer.accepts_marketing),
+            nullable(customer.total_spent.as_deref()),
+            nullable(customer.orders_count.map(|count| count as f64)),
+            nullable(customer.created_at.as_deref()),
+        ])
+}
+
+pub async fn upsert_customers(db: &D1Database, customers: &[Customer], shop: &str) -> Result<()> {
+    let statements = customers
+        .iter()
+        .map(|customer| upsert_customer_statement(db, customer, shop))
+        .collect::<worker::Result<Vec<_>>>()?;
+
+    if !statements.is_empty() {
+        db.batch(statements).await?;
+    }
+
+    Ok(())
+}
+
+pub async fn customer_by_id(db: &D1Database, id: ShopifyId) -> Result<Option<DbCustomer>> {
+    Ok(db
+        .prepare("SELECT CAST(id AS TEXT) AS id, store_name, email, first_name, last_name, phone, accepts_marketing, total_spent, orders_count, created_at FROM Customers WHERE id = ?;")
+        .bind(&[id.into()])?
+        .first::<DbCustomer>(None)
+        .await?)
+}
+
+/// Orders and checkouts of the customer are kept, with their `customer_id` cleared.
+pub async fn delete_customer(db: &D1Database, id: ShopifyId) -> Result<()> {
+    db.prepare("DELETE FROM Customers WHERE id = ?;")
+        .bind(&[id.into()])?
+        .run()
+        .await?;
+
+    Ok(())
+}
+
+fn upsert_order_statements(
+    db: &D1Database,
+    order: &Order,
+    shop: &str,
+) -> Result<Vec<D1PreparedStatement>> {
+    // The customer goes first so the order's reference to it is satisfied
+    let mut statements = Vec::new();
+    if let Some(customer) = &order.customer {
+        statements.push(upsert_customer_statement(db, customer, shop)?);
+    }
+
+    // Orders from a bulk operation come without an order number, source name and
+    // checkout token, so those keep what was stored before
+    statements.push(db
+        .prepare("INSERT INTO Orders (id, customer_id, store_name, cancelled_at, cancel_reason, name, order_number, created_at, processed_at, total_price, subtotal_price, total_tax, total_discounts, currency, presentment_currency, financial_status, fulfillment_status, tags, source_name, checkout_token) VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?) ON CONFLICT (id) DO UPDATE SET customer_id = excluded.customer_id, store_name = excluded.store_name, cancelled_at = excluded.cancelled_at, cancel_reason = excluded.cancel_reason, name = excluded.name, order_number = COALESCE(excluded.order_number, Orders.order_number), created_at = excluded.created_at, processed_at = excluded.processed_at, total_price = excluded.total_price, subtotal_price = excluded.subtotal_price, total_tax = excluded.total_tax, total_discounts = excluded.total_discounts, currency = excluded.currency, presentment_currency = excluded.presentment_currency, financial_status = excluded.financial_status, fulfillment_status = excluded.fulfillment_status, tags = excluded.tags, source_name = COALESCE(excluded.source_name, Orders.source_name), checkout_token = COALESCE(excluded.checkout_token, Orders.checkout_token), first_name = NULL, last_name = NULL, email = NULL;")
+        .bind(&[
+            order.id.into(),
+            nullable(order.customer.as_ref().map(|customer| customer.id)),
+            shop.into(),
+            nullable(order.cancelled_at.as_deref()),
+            nullable(order.cancel_reason.as_deref()),
+            order.name.as_str().into(),
+            nullable(order.order_number.map(|number| number as f64)),
+            order.created_at.as_str().into(),
+            nullable(order.processed_at.as_deref()),
+            order.total_price.as_str().into(),
+            nullable(order.subtotal_price.as_deref()),
+            nullable(order.total_tax.as_deref()),
+            nullable(order.total_discounts.as_deref()),
+            order.currency.as_str().into(),
+            nullable(order.presentment_currency.as_deref()),
+            nullable(order.financial_status.as_deref()),
+            nullable(order.fulfillment_status.as_deref()),
+            order.tags.as_str().into(),
+            nullable(order.source_name.as_deref()),
+            nullable(order.checkout_token.as_deref()),
+        ])?);
+
+    // Line items removed by an order edit have to go, so they are replaced wholesale
+    statements.push(
+        db.prepare("DELETE FROM LineItems WHERE order_id = ?;")
+            .bind(&[order.id.into()])?,
+    );
+
+    for item in &order.line_items {
+        statements.push(
+            db.prepare("INSERT INTO LineItems (id, order_id, title, product_id, variant_id, sku, vendor, quantity, price, total_discount, taxable) VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?) ON CONFLICT (id) DO UPDATE SET order_id = excluded.order_id, title = excluded.title, product_id = excluded.product_id, variant_id = excluded.variant_id, sku = excluded.sku, vendor = excluded.vendor, quantity = excluded.quantity, price = excluded.price, total_discount = excluded.total_discount, taxable = excluded.taxable;")
+                .bind(&[
+                    item.id.into(),
+                    order.id.into(),
+                    item.title.as_str().into(),
+                    nullable(item.product_id),
+                    nullable(item.variant_id),
+                    nullable(item.sku.as_deref()),
+                    nullable(item.vendor.as_deref()),
+                    (item.quantity as f64).into(),
+                    item.price.as_str().into(),
+                    item.total_discount.as_str().into(),
+                    item.taxable.into(),
+                ])?,
+        );
+    }
+
+    for fulfillment in &order.fulfillments {
+        statements.push(upsert_fulfillment_statement(db, fulfillment, shop)?);
+    }
+
+    Ok(statements)
+}
+
+/// Upserts the orders and replaces their line items in a single batch, so a
+/// redelivered order never ends up with its line items duplicated or half written.
+pub async fn upsert_orders(db: &D1Database, orders: &[Order], shop: &str) -> Result<()> {
+    let mut statements = Vec::new();
+    for order in orders {
+        statements.extend(upsert_order_statements(db, order, shop)?);
+    }
+
+    if !statements.is_empty() {
+        db.batch(statements).await?;
+    }
+
+    Ok(())
+}
+
+pub async fn orders_by_id(db: &D1Database, ids: &[ShopifyId]) -> Result<Vec<DbOrder>> {
+    Ok(db
+        .prepare("SELECT CAST(id AS TEXT) AS id, CAST(customer_id AS TEXT) AS customer_id, store_name, cancelled_at, cancel_reason, name, order_number, created_at, processed_at, total_price, subtotal_price, total_tax, total_discounts, currency, presentment_currency, financial_status, fulfillment_status, tags, source_name, checkout_token, first_name, last_name, email FROM Orders WHERE id IN (SELECT value FROM json_each(?));")
+        .bind(&[id_list(ids)?])?
+        .all()
+        .await?
+        .results::<DbOrder>()?)
+}
+
+pub async fn delete_orders(db: &D1Database, ids: &[ShopifyId]) -> Result<()> {
+    db.prepare("DELETE FROM Orders WHERE id IN (SELECT value FROM json_each(?));")
+        .bind(&[id_list(ids)?])?
+        .run()
+        .await?;
+
+    Ok(())
+}
+
+fn upsert_fulfillment_statement(
+    db: &D1Database,
+    fulfillment: &Fulfillment,
+    shop: &str,
+) -> worker::Result<D1PreparedStatement> {
+    db.prepare("INSERT INTO Fulfillments (id, order_id, store_name, status, tracking_company, tracking_numbers, tracking_urls, shipment_status, created_at, updated_at) VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?) ON CONFLICT (id) DO UPDATE SET order_id = excluded.order_id, store_name = excluded.store_name, status = excluded.status, tracking_company = excluded.tracking_company, tracking_numbers = excluded.tracking_numbers, tracking_urls = excluded.tracking_urls, shipment_status = excluded.shipment_status, created_at = excluded.created_at, updated_at = excluded.updated_at;")
+        .bind(&[
+            fulfillment.id.into(),
+            fulfillment.order_id.into(),
+            shop.into(),
+            nullable(fulfillment.status.as_deref()),
+            nullable(fulfillment.tracking_company.as_deref()),
+            serde_json::to_string(&fulfillment.tracking_numbers)?.into(),
+            serde_json::to_string(&fulfillment.tracking_urls)?.into(),
+            nullable(fulfillment.shipment_status.as_deref()),
+            nullable(fulfillment.created_at.as_deref()),
+            nullable(fulfillment.updated_at.as_deref()),
+        ])
+}
+
+pub async fn upsert_fulfillments(
+    db: &D1Database,
+    fulfillments: &[Fulfillment],
+    shop: &str,
+) -> Result<()> {
+    let statements = fulfillments
+        .iter()
+        .map(|fulfillment| upsert_fulfillment_statement(db, fulfillment, shop))
+        .collect::<worker::Result<Vec<_>>>()?;
+
+    if !statements.is_empty() {
+        db.batch(statements).await?;
+    }
+
+    Ok(())
+}
+
+fn upsert_product_statements(
+    db: &D1Database,
+    product: &Product,
+    shop: &str,
+) -> Result<Vec<D1PreparedStatement>> {
+    let mut statements = vec![db
+        .prepare("INSERT INTO Products (id, store_name, title, vendor, product_type, handle, status, tags, created_at, updated_at) VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?) ON CONFLICT (id) DO UPDATE SET store_name = excluded.store_name, title = excluded.title, vendor = excluded.vendor, product_type = excluded.product_type, handle = excluded.handle, status = excluded.status, tags = excluded.tags, created_at = excluded.created_at, updated_at = excluded.updated_at;")
+        .bind(&[
+            product.id.into(),
+            shop.into(),
+            product.title.as_str().into(),
+            nullable(product.vendor.as_deref()),
+            nullable(product.product_type.as_deref()),
+            nullable(product.handle.as_deref()),
+            nullable(product.status.as_deref()),
+            product.tags.as_str().into(),
+            nullable(product.created_at.as_deref()),
+            nullable(product.updated_at.as_deref()),
+        ])?];
+
+    // Variants deleted from the product are only noticed by their absence
+    statements.push(
+        db.prepare("DELETE FROM Variants WHERE product_id = ?;")
+            .bind(&[product.id.into()])?,
+    );
+
+    for variant in &product.variants {
+        statements.push(
+            db.prepare("INSERT INTO Variants (id, product_id, title, sku, barcode, price, compare_at_price, inventory_quantity, created_at, updated_at) VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?) ON CONFLICT (id) DO UPDATE SET product_id = excluded.product_id, title = excluded.title, sku = excluded.sku, barcode = excluded.barcode, price = excluded.price, compare_at_price = excluded.compare_at_price, inventory_quantity = excluded.inventory_quantity, created_at = excluded.created_at, updated_at = excluded.updated_at;")
+                .bind(&[
+                    variant.id.into(),
+                    product.id.into(),
+                    variant.title.as_str().into(),
+                    nullable(variant.sku.as_deref()),
+                    nullable(variant.barcode.as_deref()),
+                    variant.price.as_str().into(),
+                    nullable(variant.compare_at_price.as_deref()),
+                    nullable(variant.inventory_quantity.map(|quantity| quantity as f64)),
+                    nullable(variant.created_at.as_deref()),
+                    nullable(variant.updated_at.as_deref()),
+                ])?,
+        );
+    }
+
+    Ok(statements)
+}
+
+/// Upserts the products and replaces their variants in a single batch.
+pub async fn upsert_products(db: &D1Database, products: &[Product], shop: &str) -> Result<()> {
+    let mut statements = Vec::new();
+    for product in products {
+        statements.extend(upsert_product_statements(db, product, shop)?);
+    }
+
+    if !statements.is_empty() {
+        db.batch(statements).await?;
+    }
+
+    Ok(())
+}
+
+/// Line items keep their `product_id` and `variant_id` so past orders are unaffected.
+pub async fn delete_product(db: &D1Database, id: ShopifyId) -> Result<()> {
+    db.prepare("DELETE FROM Products WHERE id = ?;")
+        .bind(&[id.into()])?
+        .run()
+        .await?;
+
+    Ok(())
+}
+
+/// Upserts the refund with its line items and transactions in a single batch.
+pub async fn upsert_refund(db: &D1Database, refund: &Refund, shop: &str) -> Result<()> {
+    let mut statements = vec![db.prepare("INSERT INTO Refunds VALUES (?, ?, ?, ?, ?, ?) ON CONFLICT (id) DO UPDATE SET order_id = excluded.order_id, note = excluded.note, created_at = excluded.created_at, processed_at = excluded.processed_at, store_name = excluded.store_name;")
+        .bind(&[
+            refund.id.into(),
+            refund.order_id.into(),
+            nullable(refund.note.as_deref()),
+            refund.created_at.as_str().into(),
+            nullable(refund.processed_at.as_deref()),
+            shop.into(),
+        ])?];
+
+    for item in &refund.refund_line_items {
+        statements.push(
+            db.prepare("INSERT INTO RefundLineItems (id, refund_id, line_item_id, quantity, restock_type, subtotal, total_tax) VALUES (?, ?, ?, ?, ?, ?, ?) ON CONFLICT (id) DO UPDATE SET refund_id = excluded.refund_id, line_item_id = excluded.line_item_id, quantity = excluded.quantity, restock_type = excluded.restock_type, subtotal = excluded.subtotal, total_tax = excluded.total_tax;")
+                .bind(&[
+                    item.id.into(),
+                    refund.id.into(),
+                    item.line_item_id.into(),
+                    (item.quantity as f64).into(),
+                    nullable(item.restock_type.as_deref()),
+                    nullable(item.subtotal()),
+                    nullable(item.total_tax()),
+                ])?,
+        );
+    }
+
+    for transaction in &refund.transactions {
+        statements.push(upsert_transaction_statement(
+            db,
+            transaction,
+            Some(refund.id),
+            shop,
+        )?);
+    }
+
+    db.batch(statements).await?;
+
+    Ok(())
+}
+
+/// `refund_id` is only known when the transaction comes with its refund. Otherwise the
+/// one already stored is kept.
+fn upsert_transaction_statement(
+    db: &D1Database,
+    transaction: &Transaction,
+    refund_id: Option<ShopifyId>,
+    shop: &str,
+) -> worker::Result<D1PreparedStatement> {
+    db.prepare("INSERT INTO Transactions (id, order_id, store_name, kind, gateway, status, amount, currency, authorization, parent_id, processed_at, created_at, refund_id) VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?) ON CONFLICT (id) DO UPDATE SET order_id = excluded.order_id, store_name = excluded.store_name, kind = excluded.kind, gateway = excluded.gateway, status = excluded.status, amount = excluded.amount, currency = excluded.currency, authorization = excluded.authorization, parent_id = excluded.parent_id, processed_at = excluded.processed_at, created_at = excluded.created_at, refund_id = COALESCE(excluded.refund_id, Transactions.refund_id);")
+        .bind(&[
+            transaction.id.into(),
+            transaction.order_id.into(),
+            shop.into(),
+            transaction.kind.as_str().into(),
+            nullable(transaction.gateway.as_deref()),
+            nullable(transaction.status.as_deref()),
+            transaction.amount.as_str().into(),
+            nullable(transaction.currency.as_deref()),
+            nullable(transaction.authorization.as_deref()),
+            nullable(transaction.parent_id),
+            nullable(transaction.processed_at.as_deref()),
+            nullable(transaction.created_at.as_deref()),
+            nullable(refund_id),
+        ])
+}
+
+pub async fn upsert_transactions(
+    db: &D1Database,
+    transactions: &[Transaction],
+    shop: &str,
+) -> Result<()> {
+    let statements = transactions
+        .iter()
+        .map(|transaction| upsert_transaction_statement(db, transaction, None, shop))
+        .collect::<worker::Result<Vec<_>>>()?;
+
+    if !statements.is_empty() {
+        db.batch(statements).await?;
+    }
+
+    Ok(())
+}
+
+/// Upserts the payout along with the balance transactions paid out in it in a single batch.
+pub async fn upsert_payout(
+    db: &D1Database,
+    payout: &Payout,
+    transactions: &[BalanceTransaction],
+    shop: &str,
+) -> Result<()> {
+    let mut statements = vec![db
+        .prepare("INSERT INTO Payouts (id, store_name, status, date, currency, amount) VALUES (?, ?, ?, ?, ?, ?) ON CONFLICT (id) DO UPDATE SET store_name = excluded.store_name, status = excluded.status, date = excluded.date, currency = excluded.currency, amount = excluded.amount;")
+        .bind(&[
+            payout.id.into(),
+            shop.into(),
+            payout.status.as_str().into(),
+            payout.date.as_str().into(),
+            payout.currency.as_str().into(),
+            payout.amount.as_str().into(),
+        ])?];
+
+    for transaction in transactions {
+        statements.push(
+            db.prepare("INSERT INTO BalanceTransactions (id, store_name, payout_id, payout_status, type, test, currency, amount, fee, net, source_id, source_type, source_order_id, source_order_transaction_id, processed_at) VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?) ON CONFLICT (id) DO UPDATE SET store_name = excluded.store_name, payout_id = excluded.payout_id, payout_status = excluded.payout_status, type = excluded.type, test = excluded.test, currency = excluded.currency, amount = excluded.amount, fee = excluded.fee, net = excluded.net, source_id = excluded.source_id, source_type = excluded.source_type, source_order_id = excluded.source_order_id, source_order_transaction_id = excluded.source_order_transaction_id, processed_at = excluded.processed_at;")
+                .bind(&[
+                    transaction.id.into(),
+                    shop.into(),
+                    nullable(transaction.payout_id),
+                    nullable(transaction.payout_status.as_deref()),
+                    transaction.r#type.as_str().into(),
+                    transaction.test.into(),
+                    transaction.currency.as_str().into(),
+                    transaction.amount.as_str().into(),
+                    transaction.fee.as_str().into(),
+                    transaction.net.as_str().into(),
+                    nullable(transaction.source_id),
+                    nullable(transaction.source_type.as_deref()),
+                    nullable(transaction.source_order_id),
+                    nullable(transaction.source_order_transaction_id),
+                    nullable(transaction.processed_at.as_deref()),
+                ])?,
+        );
+    }
+
+    db.batch(statements).await?;
+
+    Ok(())
+}
+
+/// Returns the id of the dispute, or nothing when the stored one is newer.
+fn upsert_dispute_statement(
+    db: &D1Database,
+    dispute: &Dispute,
+    shop: &str,
+    version: Option<i64>,
+) -> worker::Result<D1PreparedStatement> {
+    db.prepare("INSERT INTO Disputes (id, order_id, type, amount, currency, reason, status, initiated_at, evidence_due_by, evidence_sent_on, store_name, updated_at) VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?) ON CONFLICT (id) DO UPDATE SET order_id = excluded.order_id, type = excluded.type, amount = excluded.amount, currency = excluded.currency, reason = excluded.reason, status = excluded.status, initiated_at = excluded.initiated_at, evidence_due_by = excluded.evidence_due_by, evidence_sent_on = excluded.evidence_sent_on, store_name = excluded.store_name, updated_at = COALESCE(excluded.updated_at, Disputes.updated_at) WHERE excluded.updated_at IS NULL OR Disputes.updated_at IS NULL OR Disputes.updated_at <= excluded.updated_at RETURNING id;")
+        .bind(&[
+            dispute.id.into(),
+            nullable(dispute.order_id),
+            dispute.r#type.as_str().into(),
+            dispute.amount.as_str().into(),
+            dispute.currency.as_str().into(),
+            dispute.reason.as_str().into(),
+            dispute.status.as_str().into(),
+            dispute.initiated_at.as_str().into(),
+            dispute.evidence_due_by.as_str().into(),
+            nullable(dispute.evidence_sent_on.as_deref()),
+            shop.into(),
+            nullable(version.map(|version| version as f64)),
+        ])
+}
+
+/// Upserts the disputes unless a newer `version` of them is already stored.
+pub async fn upsert_disputes(
+    db: &D1Database,
+    disputes: &[Dispute],
+    shop: &str,
+    version: Option<i64>,
+) -> Result<()> {
+    let statements = disputes
+        .iter()
+        .map(|dispute| upsert_dispute_statement(db, dispute, shop, version))
+        .collect::<worker::Result<Vec<_>>>()?;
+
+    if !statements.is_empty() {
+        db.batch(statements).await?;
+    }
+
+    Ok(())
+}
+
+/// Upserts the dispute unless a newer `version` of it is already stored. Returns whether
+/// it was written.
+pub async fn upsert_dispute(
+    db: &D1Database,
+    dispute: &Dispute,
+    shop: &str,
+    version: Option<i64>,
+) -> Result<bool> {
+    Ok(upsert_dispute_statement(db, dispute, shop, version)?
+        .first::<serde_json::Value>(None)
+        .await?
+        .is_some())
+}
+
+/// Disputes of active stores that need a response and have no evidence submitted yet.
+pub async fn disputes_awaiting_evidence(db: &D1Database) -> Result<Vec<DisputeAwaitingEvidence>> {
+    Ok(db.prepare("SELECT CAST(Disputes.id AS TEXT) AS id, Disputes.store_name, CAST(order_id AS TEXT) AS order_id, amount, currency, reason, evidence_due_by FROM Disputes JOIN Stores ON Stores.name = Disputes.store_name WHERE status = 'needs_response' AND evidence_sent_on IS NULL AND Stores.uninstalled_at IS NULL;")
+        .all()
+        .await?
+        .results::<DisputeAwaitingEvidence>()?)
+}
+
+pub async fn dispute_alert_sent(
+    db: &D1Database,
+    dispute_id: ShopifyId,
+    threshold_hours: i64,
+) -> Result<bool> {
+    Ok(db
+        .prepare(
+            "SELECT dispute_id FROM DisputeAlerts WHERE dispute_id = ? AND threshold_hours = ?;",
+        )
+        .bind(&[dispute_id.into(), (threshold_hours as f64).into()])?
+        .first::<serde_json::Value>(None)
+        .await?
+        .is_some())
+}
+
+pub async fn record_dispute_alert(
+    db: &D1Database,
+    dispute_id: ShopifyId,
+    threshold_hours: i64,
+    sent_at: i64,
+) -> Result<()> {
+    db.prepare("INSERT INTO DisputeAlerts VALUES (?, ?, ?) ON CONFLICT (dispute_id, threshold_hours) DO NOTHING;")
+        .bind(&[
+            dispute_id.into(),
+            (threshold_hours as f64).into(),
+            (sent_at as f64).into(),
+        ])?
+        .run()
+        .await?;
+
+    Ok(())
+}
+
+/// Replaces the stored evidence of the dispute and its file uploads in a single batch.
+pub async fn upsert_dispute_evidence(
+    db: &D1Database,
+    dispute_id: ShopifyId,
+    evidence: &DisputeEvidence,
+    fetched_at: i64,
+) -> Result<()> {
+    let mut statements = vec![db
+        .prepare("INSERT INTO DisputeEvidence (dispute_id, id, access_activity_log, billing_address, shipping_address, cancellation_policy_disclosure, cancellation_rebuttal, customer_email_address, customer_first_name, customer_last_name, product_description, refund_policy_disclosure, refund_refusal_explanation, uncategorized_text, fulfillments, submitted_by_merchant_on, created_at, updated_at, fetched_at) VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?) ON CONFLICT (dispute_id) DO UPDATE SET id = excluded.id, access_activity_log = excluded.access_activity_log, billing_address = excluded.billing_address, shipping_address = excluded.shipping_address, cancellation_policy_disclosure = excluded.cancellation_policy_disclosure, cancellation_rebuttal = excluded.cancellation_rebuttal, customer_email_address = excluded.customer_email_address, customer_first_name = excluded.customer_first_name, customer_last_name = excluded.customer_last_name, product_description = excluded.product_description, refund_policy_disclosure = excluded.refund_policy_disclosure, refund_refusal_explanation = excluded.refund_refusal_explanation, uncategorized_text = excluded.uncategorized_text, fulfillments = excluded.fulfillments, submitted_by_merchant_on = excluded.submitted_by_merchant_on, created_at = excluded.created_at, updated_at = excluded.updated_at, fetched_at = excluded.fetched_at;")
+        .bind(&[
+            dispute_id.into(),
+            evidence.id.into(),
+            nullable(evidence.access_activity_log.as_deref()),
+            nullable(evidence.billing_address.as_ref().map(ToString::to_string)),
+            nullable(evidence.shipping_address.as_ref().map(ToString::to_string)),
+            nullable(evidence.cancellation_policy_disclosure.as_deref()),
+            nullable(evidence.cancellation_rebuttal.as_deref()),
+            nullable(evidence.customer_email_address.as_deref()),
+            nullable(evidence.customer_first_name.as_deref()),
+            nullable(evidence.customer_last_name.as_deref()),
+            nullable(evidence.product_description.as_ref().map(ToString::to_string)),
+            nullable(evidence.refund_policy_disclosure.as_deref()),
+            nullable(evidence.refund_refusal_explanation.as_deref()),
+            nullable(evidence.uncategorized_text.as_deref()),
+            serde_json::to_string(&evidence.fulfillments)
+                .map_err(worker::Error::from)?
+                .into(),
+            nullable(evidence.submitted_by_merchant_on.as_deref()),
+            nullable(evidence.created_at.as_deref()),
+            nullable(evidence.updated_at.as_deref()),
+            (fetched_at as f64).into(),
+        ])?];
+
+    statements.push(
+        db.prepare("DELETE FROM DisputeFileUploads WHERE dispute_id = ?;")
+            .bind(&[dispute_id.into()])?,
+    );
+
+    for upload in &evidence.dispute_file_uploads {
+        statements.push(
+            db.prepare("INSERT INTO DisputeFileUploads (id, dispute_id, evidence_type, file_type, file_size, original_file_name, url) VALUES (?, ?, ?, ?, ?, ?, ?) ON CONFLICT (id) DO UPDATE SET dispute_id = excluded.dispute_id, evidence_type = excluded.evidence_type, file_type = excluded.file_type, file_size = excluded.file_size, original_file_name = excluded.original_file_name, url = excluded.url;")
+                .bind(&[
+                    upload.id.into(),
+                    dispute_id.into(),
+                    nullable(upload.dispute_evidence_type.as_deref()),
+                    nullable(upload.file_type.as_deref()),
+                    nullable(upload.file_size.map(|size| size as f64)),
+                    nullable(upload.original_file_name.as_deref()),
+                    nullable(upload.url.as_deref()),
+                ])?,
+        );
+    }
+
+    db.batch(statements).await?;
+
+    Ok(())
+}
+
+/// The store's disputes over the customer's orders, over the given orders, or whose
+/// evidence names the customer's email. The order ids go in as an [`id_list`].
+fn customer_dispute_params(
+    shop: &str,
+    customer_id: Option<ShopifyId>,
+    email: Option<&str>,
+    order_ids: &[ShopifyId],
+) -> worker::Result<[JsValue; 4]> {
+    Ok([
+        shop.into(),
+        nullable(customer_id),
+        id_list(order_ids)?,
+        nullable(email),
+    ])
+}
+
+/// The evidence submitted against disputes, which names the customer and their addresses.
+pub async fn dispute_evidence_by_customer(
+    db: &D1Database,
+    shop: &str,
+    customer_id: Option<ShopifyId>,
+    email: Option<&str>,
+    order_ids: &[ShopifyId],
+) -> Result<Vec<DbDisputeEvidence>> {
+    Ok(db
+        .prepare("SELECT CAST(dispute_id AS TEXT) AS dispute_id, CAST(id AS TEXT) AS id, access_activity_log, billing_address, shipping_address, cancellation_policy_disclosure, cancellation_rebuttal, customer_email_address, customer_first_name, customer_last_name, product_description, refund_policy_disclosure, refund_refusal_explanation, uncategorized_text, fulfillments, submitted_by_merchant_on, created_at, updated_at, fetched_at FROM DisputeEvidence WHERE dispute_id IN (SELECT Disputes.id FROM Disputes JOIN DisputeEvidence ON DisputeEvidence.dispute_id = Disputes.id LEFT JOIN Orders ON Orders.id = Disputes.order_id WHERE Disputes.store_name = ? AND (Orders.customer_id = ? OR Disputes.order_id IN (SELECT value FROM json_each(?)) OR DisputeEvidence.customer_email_address = ?));")
+        .bind(&customer_dispute_params(shop, customer_id, email, order_ids)?)?
+        .all()
+        .await?
+        .results::<DbDisputeEvidence>()?)
+}
+
+/// Deletes the evidence and uploaded files of the customer's disputes. The disputes
+/// themselves hold no personal data and are kept.
+pub async fn delete_dispute_evidence_by_customer(
+    db: &D1Database,
+    shop: &str,
+    customer_id: Option<ShopifyId>,
+    email: Option<&str>,
+    order_ids: &[ShopifyId],
+) -> Result<()> {
+    let params = customer_dispute_params(shop, customer_id, email, order_ids)?;
+
+    // The uploads go first as they are matched through the evidence
+    db.batch(vec![
+        db.prepare("DELETE FROM DisputeFileUploads WHERE dispute_id IN (SELECT Disputes.id FROM Disputes JOIN DisputeEvidence ON DisputeEvidence.dispute_id = Disputes.id LEFT JOIN Orders ON Orders.id = Disputes.order_id WHERE Disputes.store_name = ? AND (Orders.customer_id = ? OR Disputes.order_id IN (SELECT value FROM json_each(?)) OR DisputeEvidence.customer_email_address = ?));")
+            .bind(&params)?,
+        db.prepare("DELETE FROM DisputeEvidence WHERE dispute_id IN (SELECT Disputes.id FROM Disputes JOIN DisputeEvidence ON DisputeEvidence.dispute_id = Disputes.id LEFT JOIN Orders ON Orders.id = Disputes.order_id WHERE Disputes.store_name = ? AND (Orders.customer_id = ? OR Disputes.order_id IN (SELECT value FROM json_each(?)) OR DisputeEvidence.customer_email_address = ?));")
+            .bind(&params)?,
+    ])
+    .await?;
+
+    Ok(())
+}
+
+pub async fn upsert_abandoned_checkouts(
+    db: &D1Database,
+    checkouts: &[Checkout],
+    shop: &str,
+) -> Result<()> {
+    let mut statements = Vec::new();
+    for checkout in checkouts {
+        if let Some(customer) = &checkout.customer {
+            statements.push(upsert_customer_statement(db, customer, shop)?);
+        }
+
+        statements.push(
+            db.prepare("INSERT INTO AbandonedCheckout (id, checkout_url, customer_id, store_name, token, created_at, updated_at, completed_at, total_price, currency, email) VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?) ON CONFLICT (id) DO UPDATE SET checkout_url = excluded.checkout_url, customer_id = excluded.customer_id, store_name = excluded.store_name, token = excluded.token, created_at = excluded.created_at, updated_at = excluded.updated_at, completed_at = excluded.completed_at, total_price = excluded.total_price, currency = excluded.currency, first_name = NULL, last_name = NULL, email = excluded.email;")
+                .bind(&[
+                    checkout.id.into(),
+                    checkout.abandoned_checkout_url.as_str().into(),
+                    nullable(checkout.customer.as_ref().map(|customer| customer.id)),
+                    shop.into(),
+                    checkout.token.as_str().into(),
+                    checkout.created_at.as_str().into(),
+                    nullable(checkout.updated_at.as_deref()),
+                    nullable(checkout.completed_at.as_deref()),
+                    nullable(checkout.total_price.as_deref()),
+                    nullable(checkout.currency.as_deref()),
+                    nullable(checkout.email.as_deref()),
+                ])?,
+        );
+
+        // Checkout line items have no id so they are replaced wholesale
+        statements.push(
+            db.prepare("DELETE FROM AbandonedCheckoutLineItems WHERE checkout_id = ?;")
+                .bind(&[checkout.id.into()])?,
+        );
+
+        for item in &checkout.line_items {
+            statements.push(
+                db.prepare("INSERT INTO AbandonedCheckoutLineItems (checkout_id, key, title, variant_title, product_id, variant_id, sku, vendor, quantity, price, line_price) VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?);")
+                    .bind(&[
+                        checkout.id.into(),
+                        nullable(item.key.as_deref()),
+                        item.title.as_str().into(),
+                        nullable(item.variant_title.as_deref()),
+                        nullable(item.product_id),
+                        nullable(item.variant_id),
+                        nullable(item.sku.as_deref()),
+                        nullable(item.vendor.as_deref()),
+                        (item.quantity as f64).into(),
+                        item.price.as_str().into(),
+                        nullable(item.line_price.as_deref()),
+                    ])?,
+            );
+        }
+    }
+
+    if !statements.is_empty() {
+        db.batch(statements).await?;
+    }
+
+    Ok(())
+}
+
+/// Checkouts that haven't been synced since they referenced Customers are matched by
+/// the email copied onto them.
+/// Guest checkouts are matched by email, within the store as the same email can be a
+/// guest at other stores too.
+pub async fn abandoned_checkouts_by_customer(
+    db: &D1Database,
+    shop: &str,
+    customer_id: ShopifyId,
+    email: Option<&str>,
+) -> Result<Vec<DbAbandonedCheckout>> {
+    Ok(db
+        .prepare("SELECT CAST(id AS TEXT) AS id, checkout_url, CAST(customer_id AS TEXT) AS customer_id, store_name, token, created_at, updated_at, completed_at, total_price, currency, first_name, last_name, email FROM AbandonedCheckout WHERE store_name = ? AND (customer_id = ? OR (customer_id IS NULL AND email = ?));")
+        .bind(&[shop.into(), customer_id.into(), nullable(email)])?
+        .all()
+        .await?
+        .results::<DbAbandonedCheckout>()?)
+}
+
+/// Matches checkouts like [`abandoned_checkouts_by_customer`].
+pub async fn delete_abandoned_checkouts_by_customer(
+    db: &D1Database,
+    shop: &str,
+    customer_id: ShopifyId,
+    email: Option<&str>,
+) -> Result<()> {
+    db.prepare("DELETE FROM AbandonedCheckout WHERE store_name = ? AND (customer_id = ? OR (customer_id IS NULL AND email = ?));")
+        .bind(&[shop.into(), customer_id.into(), nullable(email)])?
+        .run()
+        .await?;
+
+    Ok(())
+}
+
+pub async fn insert_oauth_state(
+    db: &D1Database,
+    shop: &str,
+    nonce: &str,
+    expires_at: i64,
+) -> Result<()> {
+    db.prepare("INSERT INTO OAuthStates VALUES (?, ?, ?) ON CONFLICT (shop) DO UPDATE SET nonce = excluded.nonce, expires_at = excluded.expires_at;")
+        .bind(&[shop.into(), nonce.into(), (expires_at as f64).into()])?
+        .run()
+        .await?;
+
+    Ok(())
+}
+
+/// Deletes the install's nonce if it matches and hasn't expired. Returns whether it did.
+pub async fn consume_oauth_state(
+    db: &D1Database,
+    shop: &str,
+    nonce: &str,
+    now: i64,
+) -> Result<bool> {
+    Ok(db
+        .prepare("DELETE FROM OAuthStates WHERE shop = ? AND nonce = ? AND expires_at > ? RETURNING shop;")
+        .bind(&[shop.into(), nonce.into(), (now as f64).into()])?
+        .first::<serde_json::Value>(None)
+        .await?
+        .is_some())
+}
+
+pub async fn delete_expired_oauth_states(db: &D1Database, now: i64) -> Result<()> {
+    db.prepare("DELETE FROM OAuthStates WHERE expires_at <= ?;")
+        .bind(&[(now as f64).into()])?
+        .run()
+        .await?;
+
+    Ok(())
+}
+
+/// Logs a webhook delivery. Returns `false` if the event was already processed.
+pub async fn record_webhook_event(
+    db: &D1Database,
+    id: &str,
+    topic: &str,
+    shop: &str,
+    received_at: i64,
+    triggered_at: Option<&str>,
+) -> Result<bool> {
+    Ok(db
+        .prepare("INSERT INTO WebhookEvents VALUES (?, ?, ?, ?, ?, ?) ON CONFLICT (id) DO UPDATE SET received_at = excluded.received_at WHERE status != ? RETURNING id;")
+        .bind(&[
+            id.into(),
+            topic.into(),
+            shop.into(),
+            (received_at as f64).into(),
+            nullable(triggered_at),
+            EventStatus::Received.as_str().into(),
+            EventStatus::Processed.as_str().into(),
+        ])?
+        .first::<serde_json::Value>(None)
+        .await?
+        .is_some())
+}
+
+pub async fn set_webhook_event_status(
+    db: &D1Database,
+    id: &str,
+    status: EventStatus,
+) -> Result<()> {
+    db.prepare("UPDATE WebhookEvents SET status = ? WHERE id = ?;")
+        .bind(&[status.as_str().into(), id.into()])?
+        .run()
+        .await?;
+
+    Ok(())
+}
+
+/// A backfill job that still has pages to go, with the token of its store.
+#[derive(serde::Deserialize)]
+pub struct BackfillJob {
+    pub store_name: String,
+    pub access_token: String,
+    pub resource: String,
+    pub cursor: Option<String>,
+    /// Failures since the last page that went through.
+    pub attempts: i64,
+    pub updated_at: i64,
+}
+
+/// Replaces the store's jobs with a job per resource, each starting from the first page.
+pub async fn queue_backfill_jobs(
+    db: &D1Database,
+    shop: &str,
+    resources: &[Resource],
+    now: i64,
+) -> Result<()> {
+    let mut statements = vec![db
+        .prepare("DELETE FROM BackfillJobs WHERE store_name = ?;")
+        .bind(&[shop.into()])?];
+
+    for resource in resources {
+        statements.push(
+            db.prepare("INSERT INTO BackfillJobs (store_name, resource, cursor, status, attempts, last_error, updated_at) VALUES (?, ?, NULL, ?, 0, NULL, ?);")
+                .bind(&[
+                    shop.into(),
+                    resource.as_str().into(),
+                    JobStatus::Pending.as_str().into(),
+                    (now as f64).into(),
+                ])?,
+        );
+    }
+
+    db.batch(statements).await?;
+
+    Ok(())
+}
+
+pub async fn pending_backfill_jobs(db: &D1Database) -> Result<Vec<BackfillJob>> {
+    Ok(db.prepare("SELECT store_name, access_token, resource, cursor, attempts, BackfillJobs.updated_at FROM BackfillJobs JOIN Stores ON Stores.name = BackfillJobs.store_name WHERE status = ? AND Stores.uninstalled_at IS NULL;")
+        .bind(&[JobStatus::Pending.as_str().into()])?
+        .all()
+        .await?
+        .results::<BackfillJob>()?)
+}
+
+/// Takes the store's backfill until `until`. Returns `false` if another run holds it.
+pub async fn claim_backfill(db: &D1Database, shop: &str, now: i64, until: i64) -> Result<bool> {
+    Ok(db
+        .prepare("UPDATE Stores SET backfill_locked_until = ? WHERE name = ? AND (backfill_locked_until IS NULL OR backfill_locked_until <= ?) RETURNING name;")
+        .bind(&[(until as f64).into(), shop.into(), (now as f64).into()])?
+        .first::<serde_json::Value>(None)
+        .await?
+        .is_some())
+}
+
+pub async fn release_backfill(db: &D1Database, shop: &str) -> Result<()> {
+    db.prepare("UPDATE Stores SET backfill_locked_until = NULL WHERE name = ?;")
+        .bind(&[shop.into()])?
+        .run()
+        .await?;
+
+    Ok(())
+}
+
+/// Saves where the job resumes from, finishing it when there is no next page.
+pub async fn checkpoint_backfill_job(
+    db: &D1Database,
+    shop: &str,
+    resource: Resource,
+    cursor: Option<&str>,
+    now: i64,
+) -> Result<()> {
+    let status = match cursor {
+        Some(_) => JobStatus::Pending,
+        None => JobStatus::Done,
+    };
+
+    db.prepare("UPDATE BackfillJobs SET cursor = ?, status = ?, attempts = 0, last_error = NULL, updated_at = ? WHERE store_name = ? AND resource = ?;")
+        .bind(&[
+            nullable(cursor),
+            status.as_str().into(),
+            (now as f64).into(),
+            shop.into(),
+            resource.as_str().into(),
+        ])?
+        .run()
+        .await?;
+
+    Ok(())
+}
+
+/// Moves the job to another cursor without counting it as progress, e.g. while a bulk
+/// operation runs.
+pub async fn set_backfill_cursor(
+    db: &D1Database,
+    shop: &str,
+    resource: Resource,
+    cursor: Option<&str>,
+    now: i64,
+) -> Result<()> {
+    db.prepare(
+        "UPDATE BackfillJobs SET cursor = ?, updated_at = ? WHERE store_name = ? AND resource = ?;",
+    )
+    .bind(&[
+        nullable(cursor),
+        (now as f64).into(),
+        shop.into(),
+        resource.as_str().into(),
+    ])?
+    .run()
+    .await?;
+
+    Ok(())
+}
+
+/// Like [`set_backfill_cursor`], but only if the job is still at the `from` cursor.
+pub async fn replace_backfill_cursor(
+    db: &D1Database,
+    shop: &str,
+    resource: Resource,
+    from: &str,
+    to: &str,
+    now: i64,
+) -> Result<()> {
+    db.prepare("UPDATE BackfillJobs SET cursor = ?, updated_at = ? WHERE store_name = ? AND resource = ? AND cursor = ?;")
+        .bind(&[
+            to.into(),
+            (now as f64).into(),
+            shop.into(),
+            resource.as_str().into(),
+            from.into(),
+        ])?
+        .run()
+        .await?;
+
+    Ok(())
+}
+
+/// Counts a failed page against the job. Its cursor is kept so it retries the same page.
+pub async fn record_backfill_failure(
+    db: &D1Database,
+    shop: &str,
+    resource: Resource,
+    error: &str,
+    status: JobStatus,
+    now: i64,
+) -> Result<()> {
+    db.prepare("UPDATE BackfillJobs SET status = ?, attempts = attempts + 1, last_error = ?, updated_at = ? WHERE store_name = ? AND resource = ?;")
+        .bind(&[
+            status.as_str().into(),
+            error.into(),
+            (now as f64).into(),
+            shop.into(),
+            resource.as_str().into(),
+        ])?
+        .run()
+        .await?;
+
+    Ok(())
+}
+
+/// Ids are selected as `CAST(id AS TEXT)` everywhere, as D1 hands integers to JS as
+/// doubles and Shopify ids can be past the 2^53 those hold exactly. Sorting names the
+/// table's column, as the text alias would sort `"10"` before `"9"`.
+#[derive(serde::Deserialize)]
+struct Id {
+    id: ShopifyId,
+}
+
+/// The store's orders after `after`, in id order.
+pub async fn order_ids_after(
+    db: &D1Database,
+    shop: &str,
+    after: ShopifyId,
+    limit: u32,
+) -> Result<Vec<ShopifyId>> {
+    Ok(db
+        .prepare("SELECT CAST(id AS TEXT) AS id FROM Orders WHERE store_name = ? AND id > ? ORDER BY Orders.id LIMIT ?;")
+        .bind(&[shop.into(), after.into(), (limit as f64).into()])?
+        .all()
+        .await?
+        .results::<Id>()?
+        .into_iter()
+        .map(|row| row.id)
+        .collect())
+}
+
+/// The store's disputes after `after`, in id order.
+pub async fn dispute_ids_after(
+    db: &D1Database,
+    shop: &str,
+    after: ShopifyId,
+    limit: u32,
+) -> Result<Vec<ShopifyId>> {
+    Ok(db
+        .prepare("SELECT CAST(id AS TEXT) AS id FROM Disputes WHERE store_name = ? AND id > ? ORDER BY Disputes.id LIMIT ?;")
+        .bind(&[shop.into(), after.into(), (limit as f64).into()])?
+        .all()
+        .await?
+        .results::<Id>()?
+        .into_iter()
+        .map(|row| row.id)
+        .collect())
+}
@@ -0,0 +1,129 @@
+use std::fmt;
+
+use worker::wasm_bindgen::JsValue;
+
+/// The id of a Shopify resource.
+///
+/// Shopify hands these out as JSON numbers in REST payloads, as strings in some webhooks
+/// and as `gid://shopify/Order/123` in `admin_graphql_api_id`, all of which deserialize
+/// into this. They don't fit in an `f64` without losing precision so they are kept as a `u64`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, serde::Serialize)]
+#[serde(transparent)]
+pub struct ShopifyId(pub u64);
+
+impl fmt::Display for ShopifyId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+impl std::str::FromStr for ShopifyId {
+    type Err = std::num::ParseIntError;
+
+    /// Accepts both a bare id and a GID like `gid://shopify/Order/123?foo=bar`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let id = match s.strip_prefix("gid://") {
+            Some(gid) => {
+                let path = gid.split('?').next().unwrap_or_default();
+                path.rsplit('/').next().unwrap_or_default()
+            }
+            None => s,
+        };
+
+        id.parse().map(ShopifyId)
+    }
+}
+
+/// D1 can't bind a `BigInt` and a JS number would lose precision, so the id is bound as
+/// text. The INTEGER affinity of the id columns converts it back to an integer on insert.
+impl From<ShopifyId> for JsValue {
+    fn from(id: ShopifyId) -> Self {
+        JsValue::from(id.0.to_string())
+    }
+}
+
+impl<'de> serde::Deserialize<'de> for ShopifyId {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct Visitor;
+
+        impl<'de> serde::de::Visitor<'de> for Visitor {
+            type Value = ShopifyId;
+
+            fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                f.write_str("a shopify id as a number, a string or a gid")
+            }
+
+            fn visit_u64<E: serde::de::Error>(self, v: u64) -> Result<Self::Value, E> {
+                Ok(ShopifyId(v))
+            }
+
+            fn visit_i64<E: serde::de::Error>(self, v: i64) -> Result<Self::Value, E> {
+                u64::try_from(v)
+                    .map(ShopifyId)
+                    .map_err(|_| E::invalid_value(serde::de::Unexpected::Signed(v), &self))
+            }
+
+            // D1 hands integers back as JS numbers, which is why `repo` selects ids as text
+            fn visit_f64<E: serde::de::Error>(self, v: f64) -> Result<Self::Value, E> {
+                if v.fract() == 0.0 && v >= 0.0 && v <= u64::MAX as f64 {
+                    Ok(ShopifyId(v as u64))
+                } else {
+                    Err(E::invalid_value(serde::de::Unexpected::Float(v), &self))
+                }
+            }
+
+            fn visit_str<E: serde::de::Error>(self, v: &str) -> Result<Self::Value, E> {
+                v.parse()
+                    .map_err(|_| E::invalid_value(serde::de::Unexpected::Str(v), &self))
+            }
+        }
+
+        deserializer.deserialize_any(Visitor)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_a_bare_id() {
+        assert_eq!("123".parse(), Ok(ShopifyId(123)));
+    }
+
+    #[test]
+    fn parses_a_gid() {
+        assert_eq!("gid://shopify/Order/123".parse(), Ok(ShopifyId(123)));
+    }
+
+    #[test]
+    fn parses_a_gid_with_a_query_string() {
+        assert_eq!(
+            "gid://shopify/BulkOperation/123?foo=bar".parse(),
+            Ok(ShopifyId(123))
+        );
+    }
+
+    #[test]
+    fn keeps_ids_past_f64_precision() {
+        assert_eq!(
+            "9007199254740993".parse(),
+            Ok(ShopifyId(9_007_199_254_740_993))
+        );
+    }
+
+    #[test]
+    fn rejects_what_isnt_an_id() {
+        assert!("".parse::<ShopifyId>().is_err());
+        assert!("gid://shopify/Order/".parse::<ShopifyId>().is_err());
+        assert!("Order/123".parse::<ShopifyId>().is_err());
+    }
+
+    #[test]
+    fn deserializes_numbers_strings_and_gids() {
+        let ids: Vec<ShopifyId> =
+            serde_json::from_str(r#"[123, "123", "gid://shopify/Order/123"]"#).unwrap();
+
+        assert_eq!(ids, vec![ShopifyId(123); 3]);
+    }
+}
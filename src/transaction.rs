@@ -0,0 +1,74 @@
+use worker::{D1Database, Method, Request, Response, RouteContext};
+
+use crate::{
+    client, fetch, repo,
+    shopify_id::ShopifyId,
+    store_param,
+    webhook::{self, EventStatus},
+    Result, Token, DB_BINDING,
+};
+
+/// Money moving for an order: authorizations, captures, sales, voids and refunds.
+#[derive(Debug, serde::Deserialize)]
+pub struct Transaction {
+    pub(crate) id: ShopifyId,
+    pub(crate) order_id: ShopifyId,
+    /// `authorization`, `capture`, `sale`, `void` or `refund`.
+    pub(crate) kind: String,
+    pub(crate) gateway: Option<String>,
+    /// `pending`, `failure`, `success` or `error`.
+    pub(crate) status: Option<String>,
+    pub(crate) amount: String,
+    pub(crate) currency: Option<String>,
+    pub(crate) authorization: Option<String>,
+    /// The transaction this one captures, voids or refunds.
+    pub(crate) parent_id: Option<ShopifyId>,
+    pub(crate) processed_at: Option<String>,
+    pub(crate) created_at: Option<String>,
+}
+
+impl Transaction {
+    pub async fn handle_create_webhook<'a, D: 'a>(
+        mut req: Request,
+        ctx: RouteContext<D>,
+    ) -> Result<Response> {
+        let db = ctx.env.d1(DB_BINDING)?;
+
+        let (event, transaction) =
+            match webhook::receive::<Transaction>(&mut req, &ctx.env, &db).await? {
+                Ok(received) => received,
+                Err(resp) => return Ok(resp),
+            };
+        let shop = store_param(&ctx)?;
+
+        repo::upsert_transactions(&db, std::slice::from_ref(&transaction), shop).await?;
+        event.finish(&db, EventStatus::Processed).await?;
+
+        Ok(Response::ok("ok")?)
+    }
+}
+
+#[derive(serde::Deserialize)]
+pub struct Transactions {
+    transactions: Vec<Transaction>,
+}
+
+impl Transactions {
+    /// Fetches every transaction of an order. The endpoint isn't paged.
+    pub async fn fetch(token: &Token, shop: &str, order_id: ShopifyId) -> Result<Self> {
+        let mut resp = fetch(
+            token,
+            Request::new(
+                &format!("https://{shop}/admin/api/2023-01/orders/{order_id}/transactions.json"),
+                Method::Get,
+            )?,
+        )
+        .await?;
+
+        client::json(&mut resp).await
+    }
+
+    pub async fn insert_in_db(&self, db: &D1Database, shop: &str) -> Result<()> {
+        Ok(repo::upsert_transactions(db, &self.transactions, shop).await?)
+    }
+}
@@ -0,0 +1,88 @@
+//! Shopify delivers webhooks at least once, so every delivery is recorded in
+//! `WebhookEvents` under its `X-Shopify-Webhook-Id`. That lets a redelivery of an
+//! event that was already processed be acknowledged without touching the data again,
+//! and keeps an audit trail of which events were processed for each store.
+
+use worker::{D1Database, Env, Request, Response};
+
+use crate::{repo, unix_millis, verify_webhook, Error, Result};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EventStatus {
+    /// Recorded but not (yet) processed successfully. Redeliveries are processed again.
+    Received,
+    Processed,
+    /// Older than the state already stored, so it was logged instead of applied.
+    Stale,
+}
+
+impl EventStatus {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            EventStatus::Received => "received",
+            EventStatus::Processed => "processed",
+            EventStatus::Stale => "stale",
+        }
+    }
+}
+
+/// A verified webhook delivery that hasn't been processed before.
+pub struct WebhookEvent {
+    /// `None` when Shopify didn't send an `X-Shopify-Webhook-Id`, in which case the
+    /// delivery can't be deduplicated or logged.
+    id: Option<String>,
+    triggered_at: Option<String>,
+}
+
+impl WebhookEvent {
+    /// When Shopify triggered the event, from `X-Shopify-Triggered-At`, in unix milliseconds.
+    pub fn triggered_at(&self) -> Option<i64> {
+        self.triggered_at.as_deref().and_then(unix_millis)
+    }
+
+    pub async fn finish(self, db: &D1Database, status: EventStatus) -> Result<()> {
+        match &self.id {
+            Some(id) => Ok(repo::set_webhook_event_status(db, id, status).await?),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Verifies and records a webhook delivery.
+///
+/// Fails with [`Error::Unauthorized`] when the request is forged. Returns the response to
+/// send straight back when it is a redelivery of an event that was already processed
+/// (200, so Shopify stops retrying).
+pub async fn receive<T: serde::de::DeserializeOwned>(
+    req: &mut Request,
+    env: &Env,
+    db: &D1Database,
+) -> Result<Result<(WebhookEvent, T), Response>> {
+    let payload: T = match verify_webhook(req, env).await? {
+        Some(payload) => payload,
+        None => {
+            return Err(Error::Unauthorized(
+                "Failed to validate webhook hmac".to_string(),
+            ))
+        }
+    };
+
+    let headers = req.headers();
+    let id = headers.get("X-Shopify-Webhook-Id")?;
+    let triggered_at = headers.get("X-Shopify-Triggered-At")?;
+
+    if let Some(id) = &id {
+        let topic = headers.get("X-Shopify-Topic")?.unwrap_or_default();
+        let shop = headers.get("X-Shopify-Shop-Domain")?.unwrap_or_default();
+        let now = time::OffsetDateTime::now_utc().unix_timestamp();
+
+        let is_new =
+            repo::record_webhook_event(db, id, &topic, &shop, now, triggered_at.as_deref()).await?;
+
+        if !is_new {
+            return Ok(Err(Response::ok("duplicate")?));
+        }
+    }
+
+    Ok(Ok((WebhookEvent { id, triggered_at }, payload)))
+}